@@ -0,0 +1,71 @@
+#[macro_use]
+extern crate criterion;
+extern crate toolsc3k;
+
+use criterion::{BenchmarkId, Criterion, Throughput};
+use toolsc3k::format::{Image, ImageType};
+
+const WIDTH: usize = 1024;
+const HEIGHT: usize = 1024;
+
+/// A gradient with some noise, so that every pixel value is likely to occur somewhere.
+fn sample_data() -> Vec<u8> {
+    (0..WIDTH * HEIGHT)
+        .flat_map(|i| {
+            let v = ((i % WIDTH) * 64 + (i / WIDTH) * 3 + i.wrapping_mul(2654435761) % 7) as u16;
+            v.to_le_bytes().to_vec()
+        })
+        .collect()
+}
+
+fn expand(value: u16, bits: u32) -> u8 {
+    (value as u32 * 255 / ((1 << bits) - 1)) as u8
+}
+
+/// The previous conversion of a pixel, which shifts and divides every channel. It only handled the two formats that
+/// existed at the time.
+fn scalar_decoder(image_type: ImageType) -> Option<fn(u16) -> [u8; 3]> {
+    match image_type {
+        ImageType::G1R5G5B5 => Some(|color| [
+            expand((color >> 10) & 0x1F, 5),
+            expand(((color >> 4) & 0x3E) | (color >> 15), 6),
+            expand(color & 0x1F, 5),
+        ]),
+        ImageType::R5G6B5 => Some(|color| [
+            expand(color >> 11, 5),
+            expand((color >> 5) & 0x3F, 6),
+            expand(color & 0x1F, 5),
+        ]),
+        ImageType::P8 | ImageType::RGB888 | ImageType::ARGB8888 | ImageType::ARGB1555 | ImageType::ARGB4444 => None,
+    }
+}
+
+fn to_rgb8_scalar(data: &[u8], decode: fn(u16) -> [u8; 3]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(data.len() / 2 * 3);
+
+    for px in data.chunks(2) {
+        buffer.extend_from_slice(&decode(u16::from_le_bytes([px[0], px[1]])));
+    }
+
+    buffer
+}
+
+fn to_rgb8(c: &mut Criterion) {
+    let data = sample_data();
+    let mut group = c.benchmark_group("image_to_rgb8");
+    group.throughput(Throughput::Elements((WIDTH * HEIGHT) as u64));
+
+    for (image_type, decode) in ImageType::ALL.iter().filter_map(|&t| scalar_decoder(t).map(|d| (t, d))) {
+        let image = Image::new(image_type, WIDTH, HEIGHT, data.clone()).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("scalar", format!("{:?}", image_type)), &data,
+            |b, data| b.iter(|| to_rgb8_scalar(data, decode)));
+        group.bench_with_input(BenchmarkId::new("to_rgb8", format!("{:?}", image_type)), &image,
+            |b, image| b.iter(|| image.to_rgb8()));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, to_rgb8);
+criterion_main!(benches);
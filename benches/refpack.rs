@@ -0,0 +1,100 @@
+#[macro_use]
+extern crate criterion;
+extern crate toolsc3k;
+
+use criterion::{Criterion, Throughput};
+use toolsc3k::format::RefPackCompression;
+
+/// Builds a stream that is mostly back-references, both overlapping (short offsets, like runs of a single colour)
+/// and non-overlapping (long offsets, like repeated rows), which is where decoders spend their time on game data.
+fn sample_stream() -> (Vec<u8>, usize) {
+    let mut commands = Vec::new();
+    let mut len = 0;
+
+    commands.push(0xFB);
+    commands.extend((0..112u32).map(|x| (x * 31 + 7) as u8));
+    len += 112;
+
+    while len < 0x80000 {
+        // Append 1 byte, then copy 10 bytes from 2 bytes behind.
+        commands.extend_from_slice(&[0x1D, 0x01, 0x55]);
+        len += 11;
+
+        // Copy 67 bytes from 100 bytes behind.
+        commands.extend_from_slice(&[0xBF, 0x00, 99]);
+        len += 67;
+
+        // Copy 1028 bytes from 112 bytes behind.
+        commands.extend_from_slice(&[0xCC, 0x00, 111, 0xFF]);
+        len += 1028;
+    }
+
+    commands.push(0xFC);
+
+    let mut stream = vec![0x10, 0xFB, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    stream.extend(commands);
+
+    (stream, len)
+}
+
+/// The previous decoder, which pushes every byte of a back-reference into a growing `Vec`.
+fn uncompress_bytewise(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    let mut pos = 5;
+
+    while pos < data.len() {
+        let b0 = data[pos] as usize;
+        let (header_len, append_len, copy_offset, copy_len) = match b0 {
+            0x00..=0x7F => {
+                let b1 = data[pos + 1] as usize;
+                (2, b0 & 0x03, ((b0 & 0x60) << 3) + b1 + 1, ((b0 & 0x1C) >> 2) + 3)
+            },
+            0x80..=0xBF => {
+                let (b1, b2) = (data[pos + 1] as usize, data[pos + 2] as usize);
+                (3, (b1 & 0xC0) >> 6, ((b1 & 0x3F) << 8) + b2 + 1, (b0 & 0x3F) + 4)
+            },
+            0xC0..=0xDF => {
+                let (b1, b2, b3) = (data[pos + 1] as usize, data[pos + 2] as usize, data[pos + 3] as usize);
+                (4, b0 & 0x03, ((b0 & 0x10) << 12) + (b1 << 8) + b2 + 1, ((b0 & 0x0C) << 6) + b3 + 5)
+            },
+            0xE0..=0xFB => (1, ((b0 & 0x1F) << 2) + 4, 0, 0),
+            _ => (1, b0 & 0x03, 0, 0),
+        };
+
+        pos += header_len;
+        decoded.extend_from_slice(&data[pos..pos + append_len]);
+        pos += append_len;
+
+        if b0 >= 0xFC {
+            break
+        }
+
+        let start = decoded.len() - copy_offset;
+
+        for i in start..start + copy_len {
+            let b = decoded[i];
+            decoded.push(b);
+        }
+    }
+
+    decoded
+}
+
+fn uncompress(c: &mut Criterion) {
+    let (stream, len) = sample_stream();
+    let mut buffer = vec![0u8; len];
+
+    assert_eq!(uncompress_bytewise(&stream), RefPackCompression::uncompress(&stream).unwrap());
+
+    let mut group = c.benchmark_group("refpack_uncompress");
+    group.throughput(Throughput::Bytes(len as u64));
+    group.bench_function("bytewise", |b| b.iter(|| uncompress_bytewise(&stream)));
+    group.bench_function("uncompress", |b| b.iter(|| RefPackCompression::uncompress(&stream).unwrap()));
+    group.bench_function("uncompress_into", |b| {
+        b.iter(|| RefPackCompression::uncompress_into(&stream, &mut buffer).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, uncompress);
+criterion_main!(benches);
@@ -7,8 +7,9 @@ pub enum Error {
     RefPackCompression(String),
     Image(String),
     PAKFile(String),
+    POFile(String),
     Other(String),
-    OtherError(Box<StdError>),
+    OtherError(Box<dyn StdError + Send + Sync>),
 }
 
 impl fmt::Display for Error {
@@ -20,6 +21,7 @@ impl fmt::Display for Error {
             Error::RefPackCompression(ref s) => write!(f, "refpack compression error: {}", s),
             Error::Image(ref s) => write!(f, "image format error: {}", s),
             Error::PAKFile(ref s) => write!(f, "pak format error: {}", s),
+            Error::POFile(ref s) => write!(f, "po format error: {}", s),
             Error::Other(ref s) => write!(f, "error: {}", s),
             Error::OtherError(ref e) => write!(f, "error: {}", e),
         }
@@ -0,0 +1,212 @@
+use error::*;
+use format::{Image, ImageType, IXFFile, PreviewImage, QuantizeOptions};
+use image::{imageops, RgbaImage};
+use serde_json;
+
+/// Widest atlas produced unless a single frame is wider.
+pub const ATLAS_MAX_WIDTH: u32 = 1024;
+/// Empty pixels between frames.
+const ATLAS_PADDING: u32 = 1;
+
+/// Where a record's image is in an atlas.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AtlasFrame {
+    /// Index of the record in the IXF file.
+    pub record: usize,
+    pub type_id: u32,
+    pub group_id: u32,
+    pub instance_id: u32,
+    /// Pixel format of the record, which the frame is converted back to on import.
+    pub image_type: ImageType,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The JSON sidecar of an atlas image.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AtlasIndex {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<AtlasFrame>,
+}
+
+impl AtlasIndex {
+
+    /// Packs the records of `ixf` whose type is in `type_ids` into one RGBA image, tallest first, in rows. Records are
+    /// not recognized by their contents, since any body could happen to parse; a record of a listed type that does
+    /// not hold an image (see `PreviewImage`) is an error.
+    pub fn pack(ixf: &IXFFile, type_ids: &[u32]) -> Result<(RgbaImage, AtlasIndex)> {
+        let mut images = Vec::new();
+
+        for (i, r) in ixf.records.iter().enumerate().filter(|(_, r)| type_ids.contains(&r.type_id)) {
+            let preview = PreviewImage::parse(&r.body).map_err(|x| Error::Image(format!(
+                "record {:X?}_{:X?}_{:X?} is not an image: {}", r.type_id, r.group_id, r.instance_id, x)))?;
+
+            images.push((i, preview.image));
+        }
+
+        images.sort_by(|a, b| b.1.height().cmp(&a.1.height()).then(a.0.cmp(&b.0)));
+
+        let max_width = images.iter()
+            .map(|(_, image)| image.width() as u32 + ATLAS_PADDING)
+            .fold(ATLAS_MAX_WIDTH, u32::max);
+        let mut frames = Vec::with_capacity(images.len());
+        let (mut x, mut y, mut row_height, mut width) = (0, 0, 0, 0);
+
+        for &(record, ref image) in images.iter() {
+            let (w, h) = (image.width() as u32, image.height() as u32);
+
+            if x + w > max_width {
+                x = 0;
+                y += row_height + ATLAS_PADDING;
+                row_height = 0;
+            }
+
+            let r = &ixf.records[record];
+
+            frames.push(AtlasFrame {
+                record,
+                type_id: r.type_id,
+                group_id: r.group_id,
+                instance_id: r.instance_id,
+                image_type: image.image_type(),
+                x,
+                y,
+                width: w,
+                height: h,
+            });
+
+            x += w + ATLAS_PADDING;
+            row_height = row_height.max(h);
+            width = width.max(x - ATLAS_PADDING);
+        }
+
+        let height = if frames.is_empty() { 0 } else { y + row_height };
+        let mut atlas = RgbaImage::new(width, height);
+
+        for (frame, (_, image)) in frames.iter().zip(images.iter()) {
+            let pixels = RgbaImage::from_raw(frame.width, frame.height, image.to_rgba8(None)).unwrap();
+            imageops::replace(&mut atlas, &pixels, frame.x as i64, frame.y as i64);
+        }
+
+        Ok((atlas, AtlasIndex {
+            width,
+            height,
+            frames,
+        }))
+    }
+
+    /// Slices `atlas` back into the records it was packed from, keeping each record's image type and compression.
+    /// Records whose pixels come out the same keep their original bytes. Returns the number of records changed.
+    pub fn unpack(&self, atlas: &RgbaImage, ixf: &mut IXFFile, options: &QuantizeOptions) -> Result<usize> {
+        if atlas.dimensions() != (self.width, self.height) {
+            return Err(Error::Image(format!("atlas size changed: expected {}x{}, got {}x{}", self.width,
+                self.height, atlas.width(), atlas.height())));
+        }
+
+        let mut changed = 0;
+
+        for frame in self.frames.iter() {
+            let record = ixf.records.get_mut(frame.record)
+                .filter(|r| (r.type_id, r.group_id, r.instance_id) == (frame.type_id, frame.group_id,
+                    frame.instance_id))
+                .ok_or_else(|| Error::Image(format!("record {} is not {:X?}_{:X?}_{:X?}", frame.record,
+                    frame.type_id, frame.group_id, frame.instance_id)))?;
+            let mut preview = PreviewImage::parse(&record.body)?;
+
+            if (preview.image.width() as u32, preview.image.height() as u32) != (frame.width, frame.height) {
+                return Err(Error::Image(format!("frame size of record {} does not match the record", frame.record)));
+            }
+
+            let pixels = imageops::crop_imm(atlas, frame.x, frame.y, frame.width, frame.height).to_image();
+
+            let image = Image::from_rgba8_with(pixels.as_raw(), frame.width as usize, frame.height as usize,
+                frame.image_type, None, options)?;
+
+            if let Some(body) = preview.replace_image(image)? {
+                record.body = body;
+                changed += 1;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    pub fn parse_json(s: &str) -> Result<AtlasIndex> {
+        serde_json::from_str(s).map_err(|x| Error::OtherError(Box::new(x)))
+    }
+
+    pub fn as_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|x| Error::OtherError(Box::new(x)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use format::IXFRecord;
+
+    fn image_record(instance_id: u32, image_type: ImageType, width: usize, height: usize) -> IXFRecord {
+        let raw = (0..width * height * 4).map(|x| (x * 37 + instance_id as usize) as u8).collect::<Vec<u8>>();
+        let preview = PreviewImage {
+            image: Image::from_rgba8(&raw, width, height, image_type, None).unwrap(),
+            compressed: instance_id & 1 == 0,
+        };
+
+        IXFRecord {
+            type_id: 0x1234,
+            group_id: 0,
+            instance_id,
+            body: preview.as_vec().unwrap(),
+        }
+    }
+
+    #[test]
+    fn pack_unpack() {
+        let original = IXFFile {
+            records: vec![
+                image_record(1, ImageType::R5G6B5, 5, 3),
+                IXFRecord {
+                    type_id: 1,
+                    group_id: 2,
+                    instance_id: 3,
+                    body: vec![0xDE, 0xAD],
+                },
+                image_record(2, ImageType::G1R5G5B5, 4, 7),
+                image_record(3, ImageType::R5G6B5, 1100, 2),
+            ],
+        };
+
+        assert!(AtlasIndex::pack(&original, &[0x1234, 1]).is_err());
+
+        let (atlas, index) = AtlasIndex::pack(&original, &[0x1234]).unwrap();
+
+        assert_eq!(index.frames.iter().map(|f| f.record).collect::<Vec<usize>>(), vec![2, 0, 3]);
+        assert_eq!((index.frames[1].x, index.frames[1].y), (5, 0));
+        assert_eq!((index.frames[2].x, index.frames[2].y), (0, 8));
+        assert_eq!(atlas.dimensions(), (1100, 10));
+        assert_eq!(AtlasIndex::parse_json(&index.as_json().unwrap()).unwrap(), index);
+
+        let mut ixf = IXFFile {
+            records: original.records.iter().map(|r| IXFRecord {
+                type_id: r.type_id,
+                group_id: r.group_id,
+                instance_id: r.instance_id,
+                body: r.body.clone(),
+            }).collect(),
+        };
+
+        assert_eq!(index.unpack(&atlas, &mut ixf, &QuantizeOptions::default()).unwrap(), 0);
+        assert_eq!(ixf, original);
+
+        let mut edited = atlas.clone();
+        edited.put_pixel(0, 0, ::image::Rgba([0, 0, 0, 255]));
+
+        assert_eq!(index.unpack(&edited, &mut ixf, &QuantizeOptions::default()).unwrap(), 1);
+        assert_ne!(ixf.records[2], original.records[2]);
+        assert_eq!(ixf.records[0], original.records[0]);
+        assert_eq!(ixf.records[3], original.records[3]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use format::{PAKFile, PAKRecord};
+
+/// Differences between a PAK file and a translation of it. Records are matched by name (see
+/// `PAKFile::unique_names`) and lines by index.
+#[derive(Debug, Default, PartialEq)]
+pub struct PAKComparison {
+    pub missing_in_translation: Vec<String>,
+    pub missing_in_base: Vec<String>,
+    /// Records whose lines are not compared, since they do not line up.
+    pub line_counts: Vec<PAKLineCountMismatch>,
+    /// Lines identical in both files, by record name and line index. Lines without letters are never reported.
+    pub untranslated: Vec<(String, usize)>,
+    pub placeholders: Vec<PAKPlaceholderMismatch>,
+    /// Lines with letters that exist in both files, which is what `untranslated` is out of.
+    pub compared_lines: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PAKLineCountMismatch {
+    pub record: String,
+    pub base: usize,
+    pub translated: usize,
+}
+
+/// A line whose format placeholders or control characters differ. Order is ignored, since translations may reorder
+/// them.
+#[derive(Debug, PartialEq)]
+pub struct PAKPlaceholderMismatch {
+    pub record: String,
+    pub line: usize,
+    pub base: Vec<String>,
+    pub translated: Vec<String>,
+}
+
+impl PAKComparison {
+
+    pub fn compare(base: &PAKFile, translated: &PAKFile) -> PAKComparison {
+        let translated_names = translated.unique_names();
+        let translated_records = translated_names.iter()
+            .zip(translated.records.iter())
+            .map(|(name, record)| (name.as_str(), record))
+            .collect::<HashMap<&str, &PAKRecord>>();
+        let base_names = base.unique_names();
+        let base_set = base_names.iter().map(String::as_str).collect::<HashSet<&str>>();
+        let mut comparison = PAKComparison {
+            missing_in_base: translated_names.iter()
+                .filter(|n| !base_set.contains(n.as_str()))
+                .cloned()
+                .collect(),
+            ..PAKComparison::default()
+        };
+
+        for (name, record) in base_names.into_iter().zip(base.records.iter()) {
+            let other = match translated_records.get(name.as_str()) {
+                Some(other) => other,
+                None => {
+                    comparison.missing_in_translation.push(name);
+                    continue;
+                },
+            };
+
+            if record.lines.len() != other.lines.len() {
+                comparison.line_counts.push(PAKLineCountMismatch {
+                    record: name,
+                    base: record.lines.len(),
+                    translated: other.lines.len(),
+                });
+                continue;
+            }
+
+            for (i, (line, other_line)) in record.lines.iter().zip(other.lines.iter()).enumerate() {
+                if line.chars().any(char::is_alphabetic) {
+                    comparison.compared_lines += 1;
+
+                    if line == other_line {
+                        comparison.untranslated.push((name.clone(), i));
+                    }
+                }
+
+                let (placeholders, other_placeholders) = (Self::placeholders(line), Self::placeholders(other_line));
+
+                if placeholders != other_placeholders {
+                    comparison.placeholders.push(PAKPlaceholderMismatch {
+                        record: name.clone(),
+                        line: i,
+                        base: placeholders,
+                        translated: other_placeholders,
+                    });
+                }
+            }
+        }
+
+        comparison
+    }
+
+    /// The share of compared lines that are translated, from 0 to 1.
+    pub fn coverage(&self) -> f64 {
+        if self.compared_lines == 0 {
+            return 1.0;
+        }
+
+        1.0 - self.untranslated.len() as f64 / self.compared_lines as f64
+    }
+
+    /// Finds printf-style conversions (`%d`, `%1$s`, `%-5.2f`), brace placeholders (`{0}`, `{name}`) and control
+    /// characters (escaped as by `PAKRecord::escape`), sorted. The game's own conventions are not documented, so
+    /// these are the common ones.
+    pub fn placeholders(s: &str) -> Vec<String> {
+        let chars = s.chars().collect::<Vec<char>>();
+        let mut placeholders = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let end = match chars[i] {
+                '%' if chars.get(i + 1) == Some(&'%') => {
+                    i += 2;
+                    continue;
+                },
+                '%' => Self::printf_end(&chars, i + 1),
+                '{' => chars[i + 1..].iter()
+                    .take(32)
+                    .position(|&c| c == '}' || c.is_whitespace() || c == '{')
+                    .filter(|&n| n > 0 && chars[i + 1 + n] == '}')
+                    .map(|n| i + n + 2),
+                c if c.is_control() => {
+                    placeholders.push(PAKRecord::escape(&c.to_string()));
+                    i += 1;
+                    continue;
+                },
+                _ => None,
+            };
+
+            match end {
+                Some(end) => {
+                    placeholders.push(chars[i..end].iter().collect());
+                    i = end;
+                },
+                None => i += 1,
+            }
+        }
+
+        placeholders.sort();
+        placeholders
+    }
+
+    /// Where a printf conversion that starts after the `%` at `start - 1` ends, if there is one.
+    fn printf_end(chars: &[char], start: usize) -> Option<usize> {
+        let mut i = start;
+        let digits = |i: &mut usize| while chars.get(*i).is_some_and(|c| c.is_ascii_digit()) { *i += 1 };
+
+        // Argument index, as in `%1$s`.
+        digits(&mut i);
+        if chars.get(i) == Some(&'$') {
+            i += 1;
+        } else {
+            i = start;
+        }
+
+        // The space flag is left out, so that prose like "100% sure" is not a conversion.
+        while chars.get(i).is_some_and(|c| "-+#0".contains(*c)) {
+            i += 1;
+        }
+
+        if chars.get(i) == Some(&'*') { i += 1 } else { digits(&mut i) }
+
+        if chars.get(i) == Some(&'.') {
+            i += 1;
+            if chars.get(i) == Some(&'*') { i += 1 } else { digits(&mut i) }
+        }
+
+        while chars.get(i).is_some_and(|c| "hlLqjzt".contains(*c)) {
+            i += 1;
+        }
+
+        match chars.get(i) {
+            Some(c) if "diouxXeEfFgGaAcspn".contains(*c) => Some(i + 1),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+
+    fn pak(records: &[(&str, &[&str])]) -> PAKFile {
+        PAKFile::new(UTF_8, records.iter().map(|&(name, lines)| PAKRecord {
+            name: name.into(),
+            lines: lines.iter().map(|&l| l.into()).collect(),
+        }).collect())
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(PAKComparison::placeholders("%s has %d%% of %1$s, %-5.2f {0} {name} { x } {}\t\x1B"),
+            vec!["%-5.2f", "%1$s", "%d", "%s", "\\t", "\\x1B", "{0}", "{name}"]);
+        assert_eq!(PAKComparison::placeholders("100% sure, %"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn compare() {
+        let base = pak(&[
+            ("same", &["Hello", "%d citizens", "1998", "Goodbye"]),
+            ("same", &["Mayor {name}"]),
+            ("gone", &["x"]),
+            ("short", &["a", "b"]),
+        ]);
+        let translated = pak(&[
+            ("same", &["Bonjour", "%s citoyens", "1998", "Goodbye"]),
+            ("same", &["Maire {name}"]),
+            ("short", &["a"]),
+            ("new", &[]),
+        ]);
+        let comparison = PAKComparison::compare(&base, &translated);
+
+        assert_eq!(comparison, PAKComparison {
+            missing_in_translation: vec!["gone".into()],
+            missing_in_base: vec!["new".into()],
+            line_counts: vec![PAKLineCountMismatch {
+                record: "short".into(),
+                base: 2,
+                translated: 1,
+            }],
+            untranslated: vec![("same".into(), 3)],
+            placeholders: vec![PAKPlaceholderMismatch {
+                record: "same".into(),
+                line: 1,
+                base: vec!["%d".into()],
+                translated: vec!["%s".into()],
+            }],
+            compared_lines: 4,
+        });
+        assert_eq!(comparison.coverage(), 0.75);
+    }
+}
@@ -0,0 +1,113 @@
+use std::ops::Range;
+use format::ImageType;
+
+/// Most pixel pairs compared per candidate. Larger inputs are sampled evenly.
+const GUESS_MAX_SAMPLES: usize = 1 << 16;
+
+/// A candidate layout for a raw image of unknown size and format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageGuess {
+    pub image_type: ImageType,
+    pub width: usize,
+    pub height: usize,
+    /// Correlation between vertically adjacent pixels, from -1 to 1. Real images score close to 1.
+    pub score: f64,
+}
+
+impl ImageGuess {
+
+    /// Scores every combination of `widths` and `image_types` against `data`, best first. Widths that leave fewer
+    /// than two rows are skipped; trailing bytes that do not fill a row are ignored.
+    pub fn guess(data: &[u8], widths: Range<usize>, image_types: &[ImageType]) -> Vec<ImageGuess> {
+        let mut guesses = Vec::new();
+
+        for &image_type in image_types {
+            if image_type.is_paletted() {
+                continue;
+            }
+
+            let luma = Self::luma(data, image_type);
+
+            for width in widths.clone() {
+                if width == 0 || luma.len() / width < 2 {
+                    continue;
+                }
+
+                guesses.push(ImageGuess {
+                    image_type,
+                    width,
+                    height: luma.len() / width,
+                    score: Self::row_correlation(&luma, width),
+                });
+            }
+        }
+
+        guesses.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        guesses
+    }
+
+    fn luma(data: &[u8], image_type: ImageType) -> Vec<f64> {
+        data.chunks_exact(image_type.bytes_per_pixel())
+            .map(|px| {
+                let [r, g, b, _] = image_type.decode(px);
+                0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+            })
+            .collect()
+    }
+
+    /// Pearson correlation between each pixel and the pixel one row below it.
+    fn row_correlation(luma: &[f64], width: usize) -> f64 {
+        let pairs = (luma.len() / width - 1) * width;
+        let step = (pairs / GUESS_MAX_SAMPLES).max(1);
+        let (mut n, mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+
+        for i in (0..pairs).step_by(step) {
+            let (a, b) = (luma[i], luma[i + width]);
+
+            n += 1.0;
+            sum_a += a;
+            sum_b += b;
+            sum_aa += a * a;
+            sum_bb += b * b;
+            sum_ab += a * b;
+        }
+
+        let covariance = sum_ab - sum_a * sum_b / n;
+        let variance = (sum_aa - sum_a * sum_a / n) * (sum_bb - sum_b * sum_b / n);
+
+        if variance <= 0.0 {
+            // Flat data fits every width equally well.
+            return 0.0;
+        }
+
+        covariance / variance.sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use format::Image;
+
+    #[test]
+    fn guess() {
+        let (width, height) = (37, 29);
+        let raw = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = ((i % width) as f64, (i / width) as f64);
+                let v = ((x * 0.4).sin() * (y * 0.3).cos() * 120.0 + 128.0) as u8;
+                vec![v, 255 - v, (x * 6.0) as u8]
+            })
+            .collect::<Vec<u8>>();
+        let mut data = Image::from_rgb8(&raw, width, height, ImageType::R5G6B5).unwrap().into_inner();
+        data.extend_from_slice(&[0xDE, 0xAD, 0xBE]);
+
+        let guesses = ImageGuess::guess(&data, 8..100, &[ImageType::G1R5G5B5, ImageType::R5G6B5, ImageType::P8]);
+
+        assert_eq!(guesses.len(), 2 * 92);
+        assert_eq!(guesses[0].image_type, ImageType::R5G6B5);
+        assert_eq!(guesses[0].width, width);
+        assert_eq!(guesses[0].height, height);
+        assert!(guesses[0].score > 0.9);
+    }
+}
@@ -1,9 +1,14 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
 use error::*;
+use byteorder::{ByteOrder, LE};
+use image::{DynamicImage, RgbImage, RgbaImage};
 
 #[derive(Debug)]
 pub struct Image {
     image_type: ImageType,
     data: Vec<u8>,
+    palette: Vec<[u8; 4]>,
     width: usize,
     height: usize,
 }
@@ -11,7 +16,29 @@ pub struct Image {
 impl Image {
 
     pub fn new(image_type: ImageType, width: usize, height: usize, data: Vec<u8>) -> Result<Image> {
-        if data.len() != width * height * 2 {
+        if image_type.is_paletted() {
+            return Err(Error::Image("paletted images require a palette (see Image::new_paletted)".into()));
+        }
+
+        Self::with_palette(image_type, width, height, data, Vec::new())
+    }
+
+    /// Creates an 8-bit paletted image from palette indices and RGBA palette entries.
+    pub fn new_paletted(width: usize, height: usize, data: Vec<u8>, palette: Vec<[u8; 4]>) -> Result<Image> {
+        if palette.len() > 256 {
+            return Err(Error::Image(format!("too many palette entries: {} > 256", palette.len())));
+        }
+
+        if let Some(&index) = data.iter().find(|&&i| i as usize >= palette.len()) {
+            return Err(Error::Image(format!("palette index out of bounds: {} >= {}", index, palette.len())));
+        }
+
+        Self::with_palette(ImageType::P8, width, height, data, palette)
+    }
+
+    fn with_palette(image_type: ImageType, width: usize, height: usize, data: Vec<u8>, palette: Vec<[u8; 4]>)
+        -> Result<Image> {
+        if data.len() != width * height * image_type.bytes_per_pixel() {
             return Err(Error::Image(
                 format!(
                     "size of the game image data is not matched for an image with (width, height) = ({}, {})",
@@ -21,53 +48,178 @@ impl Image {
         }
 
         Ok(Image {
-            image_type: image_type,
-            data: data,
-            width: width,
-            height: height,
+            image_type,
+            data,
+            palette,
+            width,
+            height,
         })
     }
 
+    /// Decodes pixels laid out as described by `layout` inside `raw`, ignoring any bytes around or between rows.
+    pub fn from_raw(image_type: ImageType, width: usize, height: usize, raw: &[u8], layout: &ImageLayout)
+        -> Result<Image> {
+        let bpp = image_type.bytes_per_pixel();
+        let row_len = width * bpp;
+        let stride = layout.stride(row_len)?;
+        let required = layout.required_len(row_len, stride, height);
+
+        if raw.len() < required {
+            return Err(Error::Image(format!("raw image data too short: {} < {}", raw.len(), required)));
+        }
+
+        let mut data = Vec::with_capacity(row_len * height);
+
+        for y in 0..height {
+            let start = layout.offset + layout.row_index(y, height) * stride;
+            data.extend_from_slice(&raw[start..start + row_len]);
+        }
+
+        if layout.byte_order == PixelByteOrder::BigEndian {
+            for px in data.chunks_mut(bpp) {
+                px.reverse();
+            }
+        }
+
+        Self::new(image_type, width, height, data)
+    }
+
+    /// Lays the pixels out as described by `layout`. Bytes before the first row and row padding are zero.
+    pub fn to_raw(&self, layout: &ImageLayout) -> Result<Vec<u8>> {
+        let bpp = self.image_type.bytes_per_pixel();
+        let row_len = self.width * bpp;
+        let stride = layout.stride(row_len)?;
+        let mut raw = vec![0u8; layout.required_len(row_len, stride, self.height)];
+
+        for (y, row) in self.data.chunks(row_len.max(1)).enumerate() {
+            let start = layout.offset + layout.row_index(y, self.height) * stride;
+            let dest = &mut raw[start..start + row_len];
+
+            dest.copy_from_slice(row);
+
+            if layout.byte_order == PixelByteOrder::BigEndian {
+                for px in dest.chunks_mut(bpp) {
+                    px.reverse();
+                }
+            }
+        }
+
+        Ok(raw)
+    }
+
     pub fn from_rgb8(raw: &[u8], width: usize, height: usize, image_type: ImageType) -> Result<Image> {
-        if raw.len() % 3 != 0 {
+        Self::from_rgb8_with(raw, width, height, image_type, &QuantizeOptions::default())
+    }
+
+    pub fn from_rgb8_with(raw: &[u8], width: usize, height: usize, image_type: ImageType, options: &QuantizeOptions)
+        -> Result<Image> {
+        if !raw.len().is_multiple_of(3) {
             return Err(Error::Image("invalid raw RGB8 pixels (length % 3 != 0)".into()));
         }
 
-        let px_count = raw.len() / 3;
-
-        if width * height != px_count {
+        if width * height != raw.len() / 3 {
             return Err(Error::Image(format!(
                     "size of the raw RGB pixels data is not matched for an image with (width, height) = ({}, {})",
                     width, height
             )));
         }
 
-        let mut buffer = Vec::with_capacity(px_count * 2);
+        let rgba = raw.chunks(3)
+            .flat_map(|px| vec![px[0], px[1], px[2], 0xFF])
+            .collect::<Vec<u8>>();
 
-        for i in 0..px_count {
-            let r = raw[i * 3 + 0] as u16;
-            let g = raw[i * 3 + 1] as u16;
-            let b = raw[i * 3 + 2] as u16;
+        Self::from_rgba8_with(&rgba, width, height, image_type, None, options)
+    }
+
+    /// Converts RGBA pixels into a game image. For formats without an alpha channel, transparent pixels (alpha below
+    /// 128) are replaced with `color_key` if one is given, otherwise alpha is dropped.
+    pub fn from_rgba8(raw: &[u8], width: usize, height: usize, image_type: ImageType, color_key: Option<[u8; 3]>)
+        -> Result<Image> {
+        Self::from_rgba8_with(raw, width, height, image_type, color_key, &QuantizeOptions::default())
+    }
+
+    /// Like `from_rgba8`, but reduces the colour depth as specified by `options`.
+    pub fn from_rgba8_with(raw: &[u8], width: usize, height: usize, image_type: ImageType,
+        color_key: Option<[u8; 3]>, options: &QuantizeOptions) -> Result<Image> {
+        if !raw.len().is_multiple_of(4) {
+            return Err(Error::Image("invalid raw RGBA8 pixels (length % 4 != 0)".into()));
+        }
 
-            let v = match image_type {
-                ImageType::G1R5G5B5 => {
-                    ((g & 0x04) << 13) | ((r & 0xF8) << 7) | ((g & 0xF8) << 2) | (b >> 3)
-                },
-                ImageType::R5G6B5 => {
-                    ((r & 0xF8) << 8) | ((g & 0xFC) << 3) | (b >> 3)
-                },
+        let px_count = raw.len() / 4;
+
+        if width * height != px_count {
+            return Err(Error::Image(format!(
+                    "size of the raw RGBA pixels data is not matched for an image with (width, height) = ({}, {})",
+                    width, height
+            )));
+        }
+
+        let keyed;
+        let raw = match color_key {
+            Some(key) if !image_type.has_alpha() => {
+                keyed = raw.chunks(4)
+                    .flat_map(|px| if px[3] < 0x80 { vec![key[0], key[1], key[2], 0xFF] } else { px.to_vec() })
+                    .collect::<Vec<u8>>();
+                &keyed
+            },
+            _ => raw,
+        };
+
+        if image_type.is_paletted() {
+            let (data, palette) = match Self::build_palette(raw) {
+                Some(paletted) => paletted,
+                None => Self::build_r3g3b2_palette(&quantize(raw, width, [3, 3, 2, 8], options)),
             };
 
-            buffer.push((v & 0xFF) as u8);
-            buffer.push((v >> 8) as u8);
+            return Self::new_paletted(width, height, data, palette);
         }
 
-        Ok(Image {
-            image_type: image_type,
-            data: buffer,
-            width: width,
-            height: height,
-        })
+        let raw = quantize(raw, width, image_type.channel_bits(), options);
+        let bpp = image_type.bytes_per_pixel();
+        let mut buffer = vec![0u8; px_count * bpp];
+
+        for (px, out) in raw.chunks(4).zip(buffer.chunks_mut(bpp)) {
+            image_type.encode([px[0], px[1], px[2], px[3]], out);
+        }
+
+        Self::with_palette(image_type, width, height, buffer, Vec::new())
+    }
+
+    /// Uses the exact colours as the palette, if there are at most 256 of them.
+    fn build_palette(raw: &[u8]) -> Option<(Vec<u8>, Vec<[u8; 4]>)> {
+        let mut indices = HashMap::new();
+        let mut palette = Vec::new();
+        let mut data = Vec::with_capacity(raw.len() / 4);
+
+        for px in raw.chunks(4) {
+            let next = palette.len();
+            let index = *indices.entry([px[0], px[1], px[2], px[3]]).or_insert(next);
+
+            if index == palette.len() {
+                if palette.len() == 256 {
+                    return None;
+                }
+
+                palette.push([px[0], px[1], px[2], px[3]]);
+            }
+
+            data.push(index as u8);
+        }
+
+        Some((data, palette))
+    }
+
+    /// Maps pixels onto a fixed opaque R3G3B2 palette.
+    fn build_r3g3b2_palette(raw: &[u8]) -> (Vec<u8>, Vec<[u8; 4]>) {
+        let palette = (0..256usize)
+            .map(|i| [expand((i >> 5) as u16, 3), expand(((i >> 2) & 0x07) as u16, 3), expand((i & 0x03) as u16, 2),
+                0xFF])
+            .collect();
+        let data = raw.chunks(4)
+            .map(|px| ((nearest(px[0], 3) << 5) | (nearest(px[1], 3) << 2) | nearest(px[2], 2)) as u8)
+            .collect();
+
+        (data, palette)
     }
 
     pub fn image_type(&self) -> ImageType {
@@ -78,6 +230,15 @@ impl Image {
         self.data
     }
 
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// RGBA palette entries. Empty unless the image is paletted.
+    pub fn palette(&self) -> &[[u8; 4]] {
+        &self.palette
+    }
+
     pub fn width(&self) -> usize {
         self.width
     }
@@ -87,58 +248,189 @@ impl Image {
     }
 
     pub fn to_rgb8(&self) -> Vec<u8> {
-        assert!(self.data.len() % 2 == 0);
-
-        let len_half = self.data.len() / 2;
-        let mut buffer = vec![0u8; len_half * 3];
-
-        for i in 0..len_half {
-            let color = self.data[i * 2] as u16 | ((self.data[i * 2 + 1] as u16) << 8);
-
-            // I actually want to move these conversion code to an ImageType function, but I will keep it here first
-            // since I'm not sure how other formats behave.
-            let (r, g, b) = match self.image_type {
-                ImageType::G1R5G5B5 => (
-                    ((color >> 10) & 0x1F) * 255 / 31,
-                    (((color >> 4) & 0x3E) | (color >> 15)) * 255 / 63,
-                    (color & 0x1F) * 255 / 31
-                ),
-                ImageType::R5G6B5 => (
-                    (color >> 11) * 255 / 31,
-                    ((color >> 5) & 0x3F) * 255 / 63,
-                    (color & 0x1F) * 255 / 31
-                ),
-            };
+        let mut buffer = vec![0u8; self.width * self.height * 3];
 
-            buffer[i * 3 + 0] = r as u8;
-            buffer[i * 3 + 1] = g as u8;
-            buffer[i * 3 + 2] = b as u8;
+        match self.image_type.decode_table() {
+            Some(table) => {
+                for (px, out) in self.data.chunks_exact(2).zip(buffer.chunks_exact_mut(3)) {
+                    out.copy_from_slice(&table[LE::read_u16(px) as usize][..3]);
+                }
+            },
+            None => {
+                for (px, out) in self.data.chunks(self.image_type.bytes_per_pixel()).zip(buffer.chunks_exact_mut(3)) {
+                    out.copy_from_slice(&self.decode_pixel(px)[..3]);
+                }
+            },
         }
 
         buffer
     }
 
+    /// Converts the image into RGBA pixels. For formats without an alpha channel, pixels of `color_key` (after
+    /// quantizing it to the image format) become fully transparent.
+    pub fn to_rgba8(&self, color_key: Option<[u8; 3]>) -> Vec<u8> {
+        let key = match color_key {
+            Some(_) if self.image_type.has_alpha() => None,
+            Some(key) if self.image_type.is_paletted() => Some(key),
+            Some([r, g, b]) => {
+                let mut px = vec![0u8; self.image_type.bytes_per_pixel()];
+                self.image_type.encode([r, g, b, 0xFF], &mut px);
+                let [r, g, b, _] = self.image_type.decode(&px);
+                Some([r, g, b])
+            },
+            None => None,
+        };
+        let mut buffer = Vec::with_capacity(self.width * self.height * 4);
+
+        for px in self.data.chunks(self.image_type.bytes_per_pixel()) {
+            let mut rgba = self.decode_pixel(px);
+
+            if key == Some([rgba[0], rgba[1], rgba[2]]) {
+                rgba[3] = 0;
+            }
+
+            buffer.extend_from_slice(&rgba);
+        }
+
+        buffer
+    }
+
+    /// Converts the image into an RGBA8 image if it can have transparent pixels (see `to_rgba8`), otherwise an RGB8
+    /// one.
+    pub fn to_dynamic_image(&self, color_key: Option<[u8; 3]>) -> DynamicImage {
+        let (width, height) = (self.width as u32, self.height as u32);
+
+        if self.has_alpha() || color_key.is_some() {
+            DynamicImage::ImageRgba8(RgbaImage::from_raw(width, height, self.to_rgba8(color_key)).unwrap())
+        } else {
+            DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, self.to_rgb8()).unwrap())
+        }
+    }
+
+    pub fn from_dynamic_image(image: &DynamicImage, image_type: ImageType, color_key: Option<[u8; 3]>,
+        options: &QuantizeOptions) -> Result<Image> {
+        let rgba = image.to_rgba8();
+        let (width, height) = rgba.dimensions();
+
+        Self::from_rgba8_with(rgba.as_raw(), width as usize, height as usize, image_type, color_key, options)
+    }
+
+    /// Whether the image has an alpha channel or any translucent palette entry.
+    pub fn has_alpha(&self) -> bool {
+        self.image_type.has_alpha() || self.palette.iter().any(|c| c[3] != 0xFF)
+    }
+
+    fn decode_pixel(&self, px: &[u8]) -> [u8; 4] {
+        match self.image_type {
+            ImageType::P8 => self.palette[px[0] as usize],
+            image_type => image_type.decode(px),
+        }
+    }
+
     pub fn convert_to(self, image_type: ImageType) -> Image {
+        self.convert_to_with(image_type, &QuantizeOptions::default())
+    }
+
+    pub fn convert_to_with(self, image_type: ImageType, options: &QuantizeOptions) -> Image {
         if self.image_type == image_type {
             return self;
         }
 
-        Image::from_rgb8(&self.to_rgb8(), self.width, self.height, image_type).unwrap()
+        Image::from_rgba8_with(&self.to_rgba8(None), self.width, self.height, image_type, None, options).unwrap()
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RowOrder {
+    TopDown,
+    /// The last row comes first, as in BMP files.
+    BottomUp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+/// Where the pixels of an image are inside a raw blob.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageLayout {
+    /// Bytes before the first row, such as a header.
+    pub offset: usize,
+    /// Bytes from the start of a row to the start of the next one, or `None` if rows are not padded.
+    pub stride: Option<usize>,
+    pub row_order: RowOrder,
+    /// Byte order of multi-byte pixels.
+    pub byte_order: PixelByteOrder,
+}
+
+impl ImageLayout {
+
+    fn stride(&self, row_len: usize) -> Result<usize> {
+        match self.stride {
+            Some(stride) if stride < row_len => {
+                Err(Error::Image(format!("stride shorter than a row: {} < {}", stride, row_len)))
+            },
+            Some(stride) => Ok(stride),
+            None => Ok(row_len),
+        }
+    }
+
+    fn required_len(&self, row_len: usize, stride: usize, height: usize) -> usize {
+        match height {
+            0 => self.offset,
+            _ => self.offset + stride * (height - 1) + row_len,
+        }
+    }
+
+    /// Position in the blob of the `y`-th row from the top.
+    fn row_index(&self, y: usize, height: usize) -> usize {
+        match self.row_order {
+            RowOrder::TopDown => y,
+            RowOrder::BottomUp => height - 1 - y,
+        }
+    }
+}
+
+impl Default for ImageLayout {
+
+    fn default() -> ImageLayout {
+        ImageLayout {
+            offset: 0,
+            stride: None,
+            row_order: RowOrder::TopDown,
+            byte_order: PixelByteOrder::LittleEndian,
+        }
+    }
+}
+
+/// Pixel formats of game images. Multi-byte pixels are stored in little-endian order.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ImageType {
+    /// 8-bit palette indices.
+    P8,
+    RGB888,
+    ARGB8888,
+    /// Like RGB555, but the most significant bit holds the least significant bit of a 6-bit green channel.
     G1R5G5B5,
+    ARGB1555,
     R5G6B5,
+    ARGB4444,
 }
 
 impl ImageType {
 
-    pub fn as_game_value(self) -> u32 {
+    pub const ALL: [ImageType; 7] = [ImageType::P8, ImageType::RGB888, ImageType::ARGB8888, ImageType::G1R5G5B5,
+        ImageType::ARGB1555, ImageType::R5G6B5, ImageType::ARGB4444];
+
+    /// The value that stands for the format in game files. Only `G1R5G5B5` (5) and `R5G6B5` (7) are known; the game
+    /// values of the other formats have not been confirmed, so they have none.
+    pub fn as_game_value(self) -> Option<u32> {
         match self {
-            ImageType::G1R5G5B5 => 0x05,
-            ImageType::R5G6B5 => 0x07,
+            ImageType::G1R5G5B5 => Some(0x05),
+            ImageType::R5G6B5 => Some(0x07),
+            _ => None,
         }
     }
 
@@ -149,6 +441,294 @@ impl ImageType {
             _ => return Err(Error::Image(format!("unsupported image type: {}", v)))
         })
     }
+
+    /// Looks up a format by game value or by name, ignoring case, such as `7` or `argb4444`.
+    pub fn from_label(label: &str) -> Result<ImageType> {
+        if let Ok(v) = label.parse::<u32>() {
+            return Self::from_game_value(v);
+        }
+
+        Self::ALL.iter()
+            .find(|t| format!("{:?}", t).eq_ignore_ascii_case(label))
+            .cloned()
+            .ok_or_else(|| Error::Image(format!("unknown image type: {}", label)))
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            ImageType::P8 => 1,
+            ImageType::G1R5G5B5 | ImageType::ARGB1555 | ImageType::R5G6B5 | ImageType::ARGB4444 => 2,
+            ImageType::RGB888 => 3,
+            ImageType::ARGB8888 => 4,
+        }
+    }
+
+    pub fn has_alpha(self) -> bool {
+        matches!(self, ImageType::ARGB8888 | ImageType::ARGB1555 | ImageType::ARGB4444)
+    }
+
+    /// Bits per red, green, blue and alpha channel. Paletted formats report 8 bits, since their palette entries are
+    /// RGBA8.
+    pub fn channel_bits(self) -> [u32; 4] {
+        match self {
+            ImageType::P8 | ImageType::RGB888 | ImageType::ARGB8888 => [8, 8, 8, 8],
+            ImageType::G1R5G5B5 | ImageType::R5G6B5 => [5, 6, 5, 8],
+            ImageType::ARGB1555 => [5, 5, 5, 1],
+            ImageType::ARGB4444 => [4, 4, 4, 4],
+        }
+    }
+
+    pub fn is_paletted(self) -> bool {
+        self == ImageType::P8
+    }
+
+    /// Decodes a pixel of a direct-colour format into RGBA.
+    pub(crate) fn decode(self, px: &[u8]) -> [u8; 4] {
+        if let Some(table) = self.decode_table() {
+            return table[LE::read_u16(px) as usize];
+        }
+
+        match self {
+            ImageType::P8 => unreachable!("paletted pixels are decoded through the palette"),
+            ImageType::RGB888 => decode_rgb888(px),
+            ImageType::ARGB8888 => decode_argb8888(px),
+            _ => unreachable!("16-bit pixels are decoded through the lookup tables"),
+        }
+    }
+
+    /// The decoded colour of every pixel value of a 16-bit format, indexed by the little-endian value. Each table is
+    /// built on first use from the `decode_*` functions.
+    fn decode_table(self) -> Option<&'static [[u8; 4]]> {
+        static G1R5G5B5: OnceLock<Vec<[u8; 4]>> = OnceLock::new();
+        static ARGB1555: OnceLock<Vec<[u8; 4]>> = OnceLock::new();
+        static R5G6B5: OnceLock<Vec<[u8; 4]>> = OnceLock::new();
+        static ARGB4444: OnceLock<Vec<[u8; 4]>> = OnceLock::new();
+
+        let build = |decode: fn(&[u8]) -> [u8; 4]| (0..=0xFFFFu16).map(|v| decode(&v.to_le_bytes())).collect();
+
+        match self {
+            ImageType::G1R5G5B5 => Some(G1R5G5B5.get_or_init(|| build(decode_g1r5g5b5))),
+            ImageType::ARGB1555 => Some(ARGB1555.get_or_init(|| build(decode_argb1555))),
+            ImageType::R5G6B5 => Some(R5G6B5.get_or_init(|| build(decode_r5g6b5))),
+            ImageType::ARGB4444 => Some(ARGB4444.get_or_init(|| build(decode_argb4444))),
+            _ => None,
+        }
+    }
+
+    /// Encodes an RGBA colour into a pixel of a direct-colour format.
+    fn encode(self, rgba: [u8; 4], px: &mut [u8]) {
+        match self {
+            ImageType::P8 => unreachable!("paletted pixels are encoded through the palette"),
+            ImageType::RGB888 => encode_rgb888(rgba, px),
+            ImageType::ARGB8888 => encode_argb8888(rgba, px),
+            ImageType::G1R5G5B5 => encode_g1r5g5b5(rgba, px),
+            ImageType::ARGB1555 => encode_argb1555(rgba, px),
+            ImageType::R5G6B5 => encode_r5g6b5(rgba, px),
+            ImageType::ARGB4444 => encode_argb4444(rgba, px),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuantizeMode {
+    /// Round every channel to the nearest level. This is the default.
+    Nearest,
+    /// Add a 4x4 Bayer threshold pattern before rounding.
+    Ordered,
+    /// Diffuse the rounding error to neighbouring pixels (Floyd–Steinberg).
+    FloydSteinberg,
+}
+
+/// How colours are reduced to the channel depth of a game format. Alpha is always rounded to the nearest level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizeOptions {
+    pub mode: QuantizeMode,
+    /// Alternate the scan direction on every row for `FloydSteinberg`, which avoids diagonal artifacts.
+    pub serpentine: bool,
+}
+
+impl Default for QuantizeOptions {
+
+    fn default() -> QuantizeOptions {
+        QuantizeOptions {
+            mode: QuantizeMode::Nearest,
+            serpentine: false,
+        }
+    }
+}
+
+const BAYER_4X4: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+/// Reduces RGBA pixels to `bits` per channel. The result is still RGBA8, holding the 8-bit value of each chosen level
+/// so that encoding it is exact.
+fn quantize(raw: &[u8], width: usize, bits: [u32; 4], options: &QuantizeOptions) -> Vec<u8> {
+    if bits == [8, 8, 8, 8] {
+        return raw.to_vec();
+    }
+
+    let mut output = raw.to_vec();
+    let height = (raw.len() / 4).checked_div(width).unwrap_or(0);
+
+    // Alpha is never dithered: a noisy mask looks worse than a hard edge.
+    for px in output.chunks_mut(4) {
+        px[3] = expand(nearest(px[3], bits[3]) as u16, bits[3]);
+    }
+
+    match options.mode {
+        QuantizeMode::Nearest => {
+            for px in output.chunks_mut(4) {
+                for c in 0..3 {
+                    px[c] = expand(nearest(px[c], bits[c]) as u16, bits[c]);
+                }
+            }
+        },
+        QuantizeMode::Ordered => {
+            for (i, px) in output.chunks_mut(4).enumerate() {
+                let threshold = (BAYER_4X4[(i / width) % 4][(i % width) % 4] as f32 + 0.5) / 16.0;
+
+                for c in 0..3 {
+                    // Values that are already on a level stay there, so unedited images convert back unchanged.
+                    if expand(nearest(px[c], bits[c]) as u16, bits[c]) == px[c] {
+                        continue;
+                    }
+
+                    let max = ((1u32 << bits[c]) - 1) as f32;
+                    let level = (px[c] as f32 * max / 255.0 + threshold - 0.5).round().clamp(0.0, max);
+                    px[c] = expand(level as u16, bits[c]);
+                }
+            }
+        },
+        QuantizeMode::FloydSteinberg => {
+            let mut errors = vec![[0f32; 3]; width + 2];
+            let mut next_errors = vec![[0f32; 3]; width + 2];
+
+            for y in 0..height {
+                let reverse = options.serpentine && y % 2 == 1;
+
+                for i in 0..width {
+                    let x = if reverse { width - 1 - i } else { i };
+                    let px = &mut output[(y * width + x) * 4..(y * width + x) * 4 + 3];
+                    // Error buffers are offset by one so that x - 1 and x + 1 never go out of bounds.
+                    let (ahead, behind) = if reverse { (x, x + 2) } else { (x + 2, x) };
+
+                    for c in 0..3 {
+                        let max = ((1u32 << bits[c]) - 1) as f32;
+                        let value = (px[c] as f32 + errors[x + 1][c]).clamp(0.0, 255.0);
+                        let level = (value * max / 255.0).round();
+                        let quantized = expand(level as u16, bits[c]);
+                        let error = value - quantized as f32;
+
+                        px[c] = quantized;
+                        errors[ahead][c] += error * 7.0 / 16.0;
+                        next_errors[behind][c] += error * 3.0 / 16.0;
+                        next_errors[x + 1][c] += error * 5.0 / 16.0;
+                        next_errors[ahead][c] += error / 16.0;
+                    }
+                }
+
+                errors = next_errors;
+                next_errors = vec![[0f32; 3]; width + 2];
+            }
+        },
+    }
+
+    output
+}
+
+/// Rounds an 8-bit channel to the nearest `bits`-bit level.
+fn nearest(value: u8, bits: u32) -> u32 {
+    (value as u32 * ((1 << bits) - 1) + 127) / 255
+}
+
+/// Scales a `bits`-bit channel to 8 bits.
+fn expand(value: u16, bits: u32) -> u8 {
+    (value as u32 * 255 / ((1 << bits) - 1)) as u8
+}
+
+fn decode_rgb888(px: &[u8]) -> [u8; 4] {
+    [px[2], px[1], px[0], 0xFF]
+}
+
+fn encode_rgb888([r, g, b, _]: [u8; 4], px: &mut [u8]) {
+    px.copy_from_slice(&[b, g, r]);
+}
+
+fn decode_argb8888(px: &[u8]) -> [u8; 4] {
+    [px[2], px[1], px[0], px[3]]
+}
+
+fn encode_argb8888([r, g, b, a]: [u8; 4], px: &mut [u8]) {
+    px.copy_from_slice(&[b, g, r, a]);
+}
+
+fn decode_g1r5g5b5(px: &[u8]) -> [u8; 4] {
+    let color = LE::read_u16(px);
+
+    [
+        expand((color >> 10) & 0x1F, 5),
+        expand(((color >> 4) & 0x3E) | (color >> 15), 6),
+        expand(color & 0x1F, 5),
+        0xFF,
+    ]
+}
+
+/// The low bit of the 6-bit green is bit 2 of the 8-bit value, which `expand` keeps, so 16 -> 8 -> 16 is exact.
+fn encode_g1r5g5b5([r, g, b, _]: [u8; 4], px: &mut [u8]) {
+    let (r, g, b) = (r as u16, g as u16, b as u16);
+    LE::write_u16(px, ((g & 0x04) << 13) | ((r & 0xF8) << 7) | ((g & 0xF8) << 2) | (b >> 3));
+}
+
+fn decode_argb1555(px: &[u8]) -> [u8; 4] {
+    let color = LE::read_u16(px);
+
+    [
+        expand((color >> 10) & 0x1F, 5),
+        expand((color >> 5) & 0x1F, 5),
+        expand(color & 0x1F, 5),
+        expand(color >> 15, 1),
+    ]
+}
+
+fn encode_argb1555([r, g, b, a]: [u8; 4], px: &mut [u8]) {
+    let (r, g, b, a) = (r as u16, g as u16, b as u16, a as u16);
+    LE::write_u16(px, ((a & 0x80) << 8) | ((r & 0xF8) << 7) | ((g & 0xF8) << 2) | (b >> 3));
+}
+
+fn decode_r5g6b5(px: &[u8]) -> [u8; 4] {
+    let color = LE::read_u16(px);
+
+    [
+        expand(color >> 11, 5),
+        expand((color >> 5) & 0x3F, 6),
+        expand(color & 0x1F, 5),
+        0xFF,
+    ]
+}
+
+fn encode_r5g6b5([r, g, b, _]: [u8; 4], px: &mut [u8]) {
+    let (r, g, b) = (r as u16, g as u16, b as u16);
+    LE::write_u16(px, ((r & 0xF8) << 8) | ((g & 0xFC) << 3) | (b >> 3));
+}
+
+fn decode_argb4444(px: &[u8]) -> [u8; 4] {
+    let color = LE::read_u16(px);
+
+    [
+        expand((color >> 8) & 0x0F, 4),
+        expand((color >> 4) & 0x0F, 4),
+        expand(color & 0x0F, 4),
+        expand(color >> 12, 4),
+    ]
+}
+
+fn encode_argb4444([r, g, b, a]: [u8; 4], px: &mut [u8]) {
+    let (r, g, b, a) = (r as u16, g as u16, b as u16, a as u16);
+    LE::write_u16(px, ((a & 0xF0) << 8) | ((r & 0xF0) << 4) | (g & 0xF0) | (b >> 4));
 }
 
 #[cfg(test)]
@@ -171,6 +751,8 @@ mod tests {
     const DATA_0_WIDTH: usize = 3;
     const DATA_0_HEIGHT: usize = DATA_0_WIDTH;
 
+    // Grouped by colour channel.
+    #[allow(clippy::unusual_byte_groupings)]
     const DATA_0_R5G6B5: [u8; 18] = [
         0b000_00000, 0b11111_000,
         0b111_00000, 0b00000_111,
@@ -185,6 +767,7 @@ mod tests {
         0b101_01010, 0b00010_001,
     ];
 
+    #[allow(clippy::unusual_byte_groupings)]
     const DATA_0_G1R5G5B5: [u8; 18] = [
         0b000_00000, 0b0_11111_00,
         0b111_00000, 0b1_00000_11,
@@ -255,4 +838,277 @@ mod tests {
             .unwrap().convert_to(ImageType::R5G6B5);
         assert_eq!(image.into_inner(), &DATA_0_R5G6B5);
     }
+
+    #[test]
+    fn direct_formats() {
+        let colors = [
+            ([0x12, 0x34, 0x56, 0x78], ImageType::RGB888, vec![0x56, 0x34, 0x12], [0x12, 0x34, 0x56, 0xFF]),
+            ([0x12, 0x34, 0x56, 0x78], ImageType::ARGB8888, vec![0x56, 0x34, 0x12, 0x78], [0x12, 0x34, 0x56, 0x78]),
+            ([0xFF, 0x00, 0xFF, 0x80], ImageType::ARGB1555, vec![0x1F, 0xFC], [0xFF, 0x00, 0xFF, 0xFF]),
+            ([0xFF, 0x00, 0xFF, 0x7F], ImageType::ARGB1555, vec![0x1F, 0x7C], [0xFF, 0x00, 0xFF, 0x00]),
+            ([0x12, 0x34, 0x56, 0x78], ImageType::ARGB4444, vec![0x35, 0x71], [0x11, 0x33, 0x55, 0x77]),
+            ([0xFF, 0xFF, 0xFF, 0xFF], ImageType::ARGB4444, vec![0xFF, 0xFF], [0xFF, 0xFF, 0xFF, 0xFF]),
+            ([0x12, 0x34, 0x56, 0x78], ImageType::R5G6B5, vec![0xAA, 0x11], [0x10, 0x34, 0x52, 0xFF]),
+            ([0x12, 0x34, 0x56, 0x78], ImageType::G1R5G5B5, vec![0xCA, 0x88], [0x10, 0x34, 0x52, 0xFF]),
+        ];
+
+        for &(rgba, image_type, ref encoded, decoded) in colors.iter() {
+            let mut px = vec![0u8; image_type.bytes_per_pixel()];
+            image_type.encode(rgba, &mut px);
+
+            assert_eq!(&px, encoded, "{:?}", image_type);
+            assert_eq!(image_type.decode(&px), decoded, "{:?}", image_type);
+            assert_eq!(ImageType::from_label(&format!("{:?}", image_type).to_lowercase()).unwrap(), image_type);
+        }
+    }
+
+    #[test]
+    fn game_values() {
+        assert_eq!(ImageType::from_game_value(5).unwrap(), ImageType::G1R5G5B5);
+        assert_eq!(ImageType::from_game_value(7).unwrap(), ImageType::R5G6B5);
+        assert_eq!(ImageType::R5G6B5.as_game_value(), Some(7));
+        assert_eq!(ImageType::ARGB4444.as_game_value(), None);
+
+        for v in [0, 1, 3, 4, 6, 8] {
+            assert!(ImageType::from_game_value(v).is_err());
+            assert!(ImageType::from_label(&v.to_string()).is_err());
+        }
+
+        assert_eq!(ImageType::from_label("ARGB1555").unwrap(), ImageType::ARGB1555);
+        assert!(ImageType::from_label("rgb565").is_err());
+    }
+
+    #[test]
+    fn paletted() {
+        let image = Image::from_rgb8(&DATA_0, DATA_0_WIDTH, DATA_0_HEIGHT, ImageType::P8).unwrap();
+
+        assert_eq!(image.palette().len(), 9);
+        assert_eq!(image.data(), &[0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(&image.to_rgb8(), &DATA_0);
+
+        let raw = (0..1024u32).flat_map(|x| vec![x as u8, (x >> 2) as u8, (x >> 4) as u8]).collect::<Vec<u8>>();
+        let image = Image::from_rgb8(&raw, 32, 32, ImageType::P8).unwrap();
+
+        assert_eq!(image.palette().len(), 256);
+        assert_eq!(&image.to_rgb8()[..3], &[0, 0, 0]);
+        assert_eq!(&image.to_rgb8()[3 * 1023..], &[0xFF, 0xFF, 0x55]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn paletted_index_out_of_bounds() {
+        Image::new_paletted(2, 1, vec![0, 1], vec![[0, 0, 0, 0xFF]]).unwrap();
+    }
+
+    #[test]
+    fn rgba() {
+        let raw = [
+            0xFF, 0x00, 0x00, 0xFF,
+            0x00, 0xFF, 0x00, 0x00,
+            0x00, 0x00, 0xFF, 0x88,
+            0xFF, 0xFF, 0xFF, 0x11,
+        ];
+
+        let image = Image::from_rgba8(&raw, 2, 2, ImageType::ARGB4444, Some([0xFF, 0x00, 0xFF])).unwrap();
+        assert!(image.has_alpha());
+        assert_eq!(image.to_rgba8(Some([0xFF, 0x00, 0xFF])), raw.to_vec());
+        assert_eq!(image.convert_to(ImageType::ARGB8888).to_rgba8(None), raw.to_vec());
+
+        let image = Image::from_rgba8(&raw, 2, 2, ImageType::P8, None).unwrap();
+        assert!(image.has_alpha());
+        assert_eq!(image.to_rgba8(None), raw.to_vec());
+
+        let image = Image::from_rgba8(&raw, 2, 2, ImageType::ARGB1555, None).unwrap();
+        assert_eq!(image.to_rgba8(None), vec![
+            0xFF, 0x00, 0x00, 0xFF,
+            0x00, 0xFF, 0x00, 0x00,
+            0x00, 0x00, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xFF, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn color_key() {
+        let raw = [
+            0xFF, 0x00, 0x00, 0xFF,
+            0x12, 0x34, 0x56, 0x00,
+            0xFF, 0x00, 0xFF, 0xFF,
+        ];
+        let key = Some([0xFF, 0x00, 0xFF]);
+
+        let image = Image::from_rgba8(&raw, 3, 1, ImageType::R5G6B5, key).unwrap();
+        assert!(!image.has_alpha());
+        assert_eq!(image.data(), &[0x00, 0xF8, 0x1F, 0xF8, 0x1F, 0xF8]);
+        assert_eq!(image.to_rgba8(key), vec![
+            0xFF, 0x00, 0x00, 0xFF,
+            0xFF, 0x00, 0xFF, 0x00,
+            0xFF, 0x00, 0xFF, 0x00,
+        ]);
+        assert_eq!(image.to_rgba8(None)[4..8], [0xFF, 0x00, 0xFF, 0xFF]);
+
+        let image = Image::from_rgba8(&raw, 3, 1, ImageType::P8, key).unwrap();
+        assert_eq!(image.palette().len(), 2);
+        assert_eq!(image.to_rgba8(key)[4..], [0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00]);
+    }
+
+    #[test]
+    fn quantize_nearest() {
+        let rgb = [0x07u8, 0x01, 0xFC];
+        let image = Image::from_rgb8(&rgb, 1, 1, ImageType::R5G6B5).unwrap();
+        assert_eq!(image.data(), &[0x1F, 0x08]);
+
+        // Truncating instead of rounding would lose the red level.
+        let truncated = (rgb[0] as u16 >> 3) << 11 | (rgb[1] as u16 >> 2) << 5 | (rgb[2] as u16 >> 3);
+        assert_eq!(truncated.to_le_bytes(), [0x1F, 0x00]);
+    }
+
+    #[test]
+    fn quantize_dithered() {
+        let raw = [0x0Cu8, 0x0C, 0x0C].iter().cycle().cloned().take(16 * 16 * 3).collect::<Vec<u8>>();
+        let mean_red = |options: &QuantizeOptions| {
+            let image = Image::from_rgb8_with(&raw, 16, 16, ImageType::R5G6B5, options).unwrap();
+            let rgb = image.to_rgb8();
+            rgb.chunks(3).map(|px| px[0] as f32).sum::<f32>() / 256.0
+        };
+
+        assert_eq!(mean_red(&QuantizeOptions::default()), 8.0);
+
+        for &mode in &[QuantizeMode::Ordered, QuantizeMode::FloydSteinberg] {
+            for &serpentine in &[false, true] {
+                let mean = mean_red(&QuantizeOptions { mode, serpentine });
+                assert!((mean - 12.0).abs() < 1.0, "{:?}: {}", mode, mean);
+            }
+        }
+
+        let options = QuantizeOptions { mode: QuantizeMode::FloydSteinberg, serpentine: false };
+        let image = Image::from_rgb8(&raw, 16, 16, ImageType::ARGB8888).unwrap()
+            .convert_to_with(ImageType::R5G6B5, &options);
+        assert_eq!(image.data(), Image::from_rgb8_with(&raw, 16, 16, ImageType::R5G6B5, &options).unwrap().data());
+    }
+
+    #[test]
+    fn dynamic_image() {
+        let image = Image::new(ImageType::R5G6B5, DATA_0_WIDTH, DATA_0_HEIGHT, DATA_0_R5G6B5.to_vec()).unwrap();
+        let dynamic = image.to_dynamic_image(None);
+
+        assert_eq!(dynamic.as_rgb8().unwrap().as_raw(), &DATA_0_CONVERSION_LOSS.to_vec());
+
+        let image = Image::from_dynamic_image(&dynamic, ImageType::R5G6B5, None, &QuantizeOptions::default()).unwrap();
+        assert_eq!(image.into_inner(), DATA_0_R5G6B5);
+
+        let image = Image::new(ImageType::ARGB4444, 1, 1, vec![0x34, 0x12]).unwrap();
+        assert_eq!(image.to_dynamic_image(None).as_rgba8().unwrap().as_raw(), &vec![0x22, 0x33, 0x44, 0x11]);
+    }
+
+    #[test]
+    fn layout() {
+        let layout = ImageLayout {
+            offset: 3,
+            stride: Some(8),
+            row_order: RowOrder::BottomUp,
+            byte_order: PixelByteOrder::BigEndian,
+        };
+        let raw = [
+            0xAA, 0xBB, 0xCC,
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0xEE, 0xEE,
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0xEE, 0xEE,
+        ];
+
+        let image = Image::from_raw(ImageType::R5G6B5, 3, 2, &raw, &layout).unwrap();
+        assert_eq!(image.data(), &[0x02, 0x01, 0x04, 0x03, 0x06, 0x05, 0x22, 0x11, 0x44, 0x33, 0x66, 0x55]);
+
+        // The padding after the last row is not written.
+        let mut expected = raw[..raw.len() - 2].to_vec();
+        for b in expected.iter_mut().filter(|b| **b >= 0xAA) {
+            *b = 0;
+        }
+        assert_eq!(image.to_raw(&layout).unwrap(), expected);
+
+        let image = Image::from_raw(ImageType::R5G6B5, 3, 2, &raw[3..15], &ImageLayout::default()).unwrap();
+        assert_eq!(image.to_raw(&ImageLayout::default()).unwrap(), &raw[3..15]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn layout_too_short() {
+        let layout = ImageLayout {
+            stride: Some(8),
+            ..ImageLayout::default()
+        };
+
+        Image::from_raw(ImageType::R5G6B5, 3, 2, &[0u8; 13], &layout).unwrap();
+    }
+
+    /// Exporting every 16-bit value and importing it again must give back the same bits, whatever the dithering.
+    #[test]
+    fn lossless_roundtrip() {
+        let data = (0..=0xFFFFu16).flat_map(|v| v.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+        let modes = [QuantizeMode::Nearest, QuantizeMode::Ordered, QuantizeMode::FloydSteinberg];
+        let image_types = [ImageType::G1R5G5B5, ImageType::ARGB1555, ImageType::R5G6B5, ImageType::ARGB4444];
+
+        for &image_type in image_types.iter() {
+            let image = Image::new(image_type, 256, 256, data.clone()).unwrap();
+
+            for &mode in modes.iter() {
+                let options = QuantizeOptions {
+                    mode,
+                    serpentine: true,
+                };
+                let exported = image.to_dynamic_image(None);
+                let imported = Image::from_dynamic_image(&exported, image_type, None, &options).unwrap();
+                assert!(imported.data() == &data[..], "{:?} with {:?}", image_type, mode);
+
+                let imported = Image::from_rgba8_with(&image.to_rgba8(None), 256, 256, image_type, None, &options)
+                    .unwrap();
+                assert!(imported.data() == &data[..], "{:?} with {:?}", image_type, mode);
+
+                if !image_type.has_alpha() {
+                    let imported = Image::from_rgb8_with(&image.to_rgb8(), 256, 256, image_type, &options).unwrap();
+                    assert!(imported.data() == &data[..], "{:?} with {:?}", image_type, mode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_table() {
+        let data = (0..=0xFFFFu16).flat_map(|v| v.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+        fn scale(v: u16, max: u16) -> u8 {
+            (v * 255 / max) as u8
+        }
+
+        // The conversions before the lookup tables, written out per channel.
+        let references = [
+            (ImageType::G1R5G5B5, (|c: u16| [
+                scale((c >> 10) & 0x1F, 31),
+                scale(((c >> 4) & 0x3E) | (c >> 15), 63),
+                scale(c & 0x1F, 31),
+                0xFF,
+            ]) as fn(u16) -> [u8; 4]),
+            (ImageType::R5G6B5, |c| [scale(c >> 11, 31), scale((c >> 5) & 0x3F, 63), scale(c & 0x1F, 31), 0xFF]),
+            (ImageType::ARGB1555, |c| [
+                scale((c >> 10) & 0x1F, 31),
+                scale((c >> 5) & 0x1F, 31),
+                scale(c & 0x1F, 31),
+                if c & 0x8000 != 0 { 0xFF } else { 0 },
+            ]),
+            (ImageType::ARGB4444, |c| [
+                ((c >> 8) & 0x0F) as u8 * 17,
+                ((c >> 4) & 0x0F) as u8 * 17,
+                (c & 0x0F) as u8 * 17,
+                (c >> 12) as u8 * 17,
+            ]),
+        ];
+
+        for &(image_type, reference) in references.iter() {
+            let expected = (0..=0xFFFFu16).flat_map(|c| reference(c).to_vec()).collect::<Vec<u8>>();
+            let image = Image::new(image_type, 256, 256, data.clone()).unwrap();
+            let rgb = expected.chunks(4).flat_map(|px| px[..3].to_vec()).collect::<Vec<u8>>();
+
+            assert!(image.to_rgba8(None) == expected, "{:?}", image_type);
+            assert!(image.to_rgb8() == rgb, "{:?}", image_type);
+        }
+
+        assert_eq!(ImageType::RGB888.decode_table(), None);
+    }
 }
@@ -1,6 +1,8 @@
 use std::io::{self, Read, Write, Cursor};
 use error::*;
+use format::{RefPackCompression, RefPackMode};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use rayon::{ThreadPoolBuilder, prelude::*};
 
 pub const IXF_FILE_HEADER_IDENTIFIER: &[u8] = &[0xD7, 0x81, 0xC3, 0x80];
 pub const IXF_FILE_RECORD_LENGTH: usize = 20;
@@ -55,15 +57,15 @@ impl IXFFile {
             }
 
             records.push(IXFRecord {
-                type_id: type_id,
-                group_id: group_id,
-                instance_id: instance_id,
+                type_id,
+                group_id,
+                instance_id,
                 body: data[address..address + length].to_vec(),
             });
         }
 
         Ok(IXFFile {
-            records: records,
+            records,
         })
     }
 
@@ -92,6 +94,62 @@ impl IXFFile {
 
         Ok(cursor.into_inner())
     }
+
+    /// Uncompresses every RefPack-compressed record body on a pool of `jobs` threads (0 uses one per CPU). Returns
+    /// which records were compressed, in record order, which `compress_selected` takes to restore them.
+    pub fn uncompress_records(&mut self, jobs: usize) -> Result<Vec<bool>> {
+        let compressed = self.records.iter()
+            .map(|r| RefPackCompression::is_compressed(&r.body))
+            .collect::<Vec<bool>>();
+
+        self.map_bodies(jobs, |_, body| if RefPackCompression::is_compressed(body) {
+            RefPackCompression::uncompress(body).map(Some)
+        } else {
+            Ok(None)
+        })?;
+
+        Ok(compressed)
+    }
+
+    /// Compresses every record body that is not RefPack-compressed yet on a pool of `jobs` threads (0 uses one per
+    /// CPU). The output does not depend on the number of threads.
+    pub fn compress_records(&mut self, mode: RefPackMode, jobs: usize) -> Result<()> {
+        self.compress_selected(mode, jobs, &vec![true; self.records.len()])
+    }
+
+    /// Like `compress_records`, but only for the records whose index is true in `selection`. Records past the end of
+    /// `selection` are left alone.
+    pub fn compress_selected(&mut self, mode: RefPackMode, jobs: usize, selection: &[bool]) -> Result<()> {
+        self.map_bodies(jobs, |i, body| if !selection.get(i).cloned().unwrap_or(false)
+            || RefPackCompression::is_compressed(body) {
+            Ok(None)
+        } else {
+            RefPackCompression::compress_with_mode(body, mode).map(Some)
+        })
+    }
+
+    fn map_bodies<F>(&mut self, jobs: usize, f: F) -> Result<()>
+        where F: Fn(usize, &[u8]) -> Result<Option<Vec<u8>>> + Sync {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .map_err(|x| Error::OtherError(Box::new(x)))?;
+
+        let bodies = pool.install(|| {
+            self.records.par_iter()
+                .enumerate()
+                .map(|(i, r)| f(i, &r.body))
+                .collect::<Result<Vec<Option<Vec<u8>>>>>()
+        })?;
+
+        for (record, body) in self.records.iter_mut().zip(bodies) {
+            if let Some(body) = body {
+                record.body = body;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -202,4 +260,60 @@ mod tests {
 
         assert_eq!(parsed.as_vec().unwrap(), data.to_vec());
     }
+
+    fn sample_file() -> IXFFile {
+        IXFFile {
+            records: (0..32u32).map(|i| IXFRecord {
+                type_id: 0x1234,
+                group_id: i,
+                instance_id: 0,
+                body: (0..i * 100).map(|x| (x % (i + 3)) as u8).collect(),
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn compress_records() {
+        let original = sample_file();
+        let mut single = sample_file();
+        let mut parallel = sample_file();
+
+        single.compress_records(RefPackMode::Greedy, 1).unwrap();
+        parallel.compress_records(RefPackMode::Greedy, 4).unwrap();
+
+        assert_eq!(single, parallel);
+        assert!(parallel.records.iter().all(|r| RefPackCompression::is_compressed(&r.body)));
+
+        parallel.records.push(IXFRecord {
+            type_id: 0x5678,
+            group_id: 0,
+            instance_id: 0,
+            body: vec![0xBE, 0xEF],
+        });
+
+        let compressed = parallel.uncompress_records(4).unwrap();
+
+        assert_eq!(compressed.len(), 33);
+        assert!(compressed[..32].iter().all(|&c| c));
+        assert!(!compressed[32]);
+        assert_eq!(parallel.records.pop().unwrap().body, vec![0xBE, 0xEF]);
+        assert_eq!(parallel, original);
+    }
+
+    #[test]
+    fn compress_selected() {
+        let mut original = sample_file();
+        let selection = (0..32).map(|i| i % 3 == 0).collect::<Vec<bool>>();
+
+        original.compress_selected(RefPackMode::Greedy, 1, &selection).unwrap();
+
+        let data = original.as_vec().unwrap();
+        let mut ixf = IXFFile::parse(&data, false).unwrap();
+
+        assert_eq!(ixf.uncompress_records(4).unwrap(), selection);
+        assert_eq!(ixf, sample_file());
+
+        ixf.compress_selected(RefPackMode::Greedy, 4, &selection).unwrap();
+        assert_eq!(ixf.as_vec().unwrap(), data);
+    }
 }
@@ -1,9 +1,21 @@
+mod atlas;
+mod compare;
 mod ixf;
 mod refpack;
 mod image;
+mod guess;
 mod pak;
+mod po;
+mod preview;
+mod search;
 
+pub use self::atlas::*;
+pub use self::compare::*;
 pub use self::ixf::*;
 pub use self::refpack::*;
 pub use self::image::*;
+pub use self::guess::*;
 pub use self::pak::*;
+pub use self::po::*;
+pub use self::preview::*;
+pub use self::search::*;
@@ -1,27 +1,376 @@
-use std::io::{self, Read};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor, Read, Write};
 use error::*;
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
+use serde::{Deserialize, Deserializer, Serializer};
+use serde::de::Error as DeError;
+use {csv, serde_json, toml};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct PAKFile {
+    /// Code page of the names and lines in the file. Localized versions of the game use legacy code pages, such as
+    /// Windows-1252 or Shift-JIS.
+    #[serde(serialize_with = "serialize_encoding", deserialize_with = "deserialize_encoding")]
+    pub encoding: &'static Encoding,
+    /// For each record, the index of the first record that points at the same line block, as read from the file.
+    /// Empty when every record has a block of its own. `as_vec` keeps this sharing (see `PAKSharing::Blocks`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_blocks: Vec<usize>,
     pub records: Vec<PAKRecord>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct PAKRecord {
     pub name: String,
     pub lines: Vec<String>,
 }
 
+/// How records share line blocks when a PAK file is written. Name table entries point at line blocks by offset, so
+/// records with identical lines can point at the same block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PAKSharing {
+    /// Every record gets its own block.
+    None,
+    /// Records with identical lines share the block of the first of them.
+    Identical,
+    /// Each record shares the block of the record at its index (see `PAKFile::shared_blocks`). Records that point at
+    /// themselves, at a later record, or at a record whose lines are no longer identical get their own block.
+    Blocks(Vec<usize>),
+}
+
+/// A record whose strings are not decoded yet.
+type RawRecord = (Vec<u8>, Vec<Vec<u8>>);
+
 impl PAKFile {
 
+    pub fn new(encoding: &'static Encoding, records: Vec<PAKRecord>) -> PAKFile {
+        PAKFile {
+            encoding,
+            shared_blocks: Vec::new(),
+            records,
+        }
+    }
+
+    /// Parses a PAK file, detecting its encoding (see `detect_encoding`).
     pub fn parse(data: &[u8]) -> Result<PAKFile> {
+        let (records, shared_blocks) = Self::parse_raw(data)?;
+        let encoding = Self::detect_raw_encoding(&records);
+
+        Self::decode_records(records, shared_blocks, encoding)
+    }
+
+    /// Parses a PAK file whose strings are in `encoding`. Bytes that are invalid in the encoding are an error.
+    pub fn parse_with_encoding(data: &[u8], encoding: &'static Encoding) -> Result<PAKFile> {
+        let (records, shared_blocks) = Self::parse_raw(data)?;
+
+        Self::decode_records(records, shared_blocks, encoding)
+    }
+
+    /// Guesses the encoding of the strings in a PAK file. Files that are valid UTF-8 are UTF-8, which includes files in
+    /// plain ASCII (see `is_ascii`).
+    pub fn detect_encoding(data: &[u8]) -> Result<&'static Encoding> {
+        Ok(Self::detect_raw_encoding(&Self::parse_raw(data)?.0))
+    }
+
+    /// Looks up an encoding by a WHATWG label (such as `windows-1252`, `tis-620` or `shift_jis`) or a `cpNNN` code
+    /// page name.
+    pub fn encoding_for_label(label: &str) -> Result<&'static Encoding> {
+        let label = label.trim().to_ascii_lowercase();
+        let encoding = Encoding::for_label(label.as_bytes())
+            .or_else(|| {
+                let code_page = label.strip_prefix("cp")?;
+                Encoding::for_label(format!("windows-{}", code_page).as_bytes())
+            })
+            .ok_or_else(|| Error::PAKFile(format!("unknown encoding: {}", label)))?;
+
+        // UTF-16 and a few others can only be decoded.
+        if encoding.output_encoding() != encoding {
+            return Err(Error::PAKFile(format!("unsupported encoding: {}", encoding.name())));
+        }
+
+        Ok(encoding)
+    }
+
+    /// Writes the record count and the name table, then the line blocks in record order. Records share blocks as
+    /// told by `shared_blocks`. Strings that cannot be represented in `encoding` are an error.
+    pub fn as_vec(&self) -> Result<Vec<u8>> {
+        if self.shared_blocks.is_empty() {
+            self.as_vec_with_sharing(&PAKSharing::None)
+        } else {
+            self.as_vec_with_sharing(&PAKSharing::Blocks(self.shared_blocks.clone()))
+        }
+    }
+
+    /// Like `as_vec`, but lets records point at the same line block as told by `sharing` instead of `shared_blocks`.
+    pub fn as_vec_with_sharing(&self, sharing: &PAKSharing) -> Result<Vec<u8>> {
+        let owners = self.block_owners(sharing);
+        let names = self.records.iter()
+            .map(|r| self.encode_string(&r.name))
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+        let blocks = self.records.iter()
+            .zip(owners.iter().enumerate())
+            .filter(|&(_, (i, &owner))| i == owner)
+            .map(|(r, _)| r.lines.iter().map(|l| self.encode_string(l)).collect())
+            .collect::<Result<Vec<Vec<Vec<u8>>>>>()?;
+        let mut block_offsets = Vec::with_capacity(blocks.len());
+        let mut offset = 4 + names.iter().map(|n| 8 + n.len()).sum::<usize>();
+
+        for lines in blocks.iter() {
+            block_offsets.push(offset);
+            offset += 4 + lines.iter().map(|l| 4 + l.len()).sum::<usize>();
+        }
+
+        let mut offsets = Vec::with_capacity(owners.len());
+        let mut next_block = block_offsets.iter();
+
+        for (i, &owner) in owners.iter().enumerate() {
+            let offset = if i == owner { *next_block.next().unwrap() } else { offsets[owner] };
+            offsets.push(offset);
+        }
+
+        let mut cursor = Cursor::new(Vec::new());
+
+        cursor.write_u32::<LE>(self.records.len() as u32)?;
+
+        for (name, &offset) in names.iter().zip(offsets.iter()) {
+            Self::write_string(&mut cursor, name)?;
+            cursor.write_u32::<LE>(offset as u32)?;
+        }
+
+        for lines in blocks.iter() {
+            cursor.write_u32::<LE>(lines.len() as u32)?;
+
+            for line in lines.iter() {
+                Self::write_string(&mut cursor, line)?;
+            }
+        }
+
+        Ok(cursor.into_inner())
+    }
+
+    /// Whether every name and line is ASCII. Such a file reads the same in any of the game's code pages, so the code
+    /// page it was made for cannot be told from it.
+    pub fn is_ascii(&self) -> bool {
+        self.records.iter().all(|r| r.name.is_ascii() && r.lines.iter().all(|l| l.is_ascii()))
+    }
+
+    /// The first record named `name`. Each call searches the records in order; use `index` to look up many names.
+    pub fn get(&self, name: &str) -> Option<&PAKRecord> {
+        self.records.iter().find(|r| r.name == name)
+    }
+
+    /// A map from each name to the index of the first record with it. The map is not updated when records change.
+    pub fn index(&self) -> HashMap<&str, usize> {
+        let mut index = HashMap::with_capacity(self.records.len());
+
+        for (i, record) in self.records.iter().enumerate() {
+            index.entry(record.name.as_str()).or_insert(i);
+        }
+
+        index
+    }
+
+    /// The record names, made unique: `~` in a name is doubled, and records that share a name are told apart by
+    /// number, as in `name`, `name~2`, `name~3`. A record named `name~2` becomes `name~~2`, so the two cannot clash.
+    pub fn unique_names(&self) -> Vec<String> {
+        let mut seen = HashMap::new();
+
+        self.records.iter()
+            .map(|record| {
+                let count = seen.entry(record.name.as_str()).or_insert(0);
+                let name = record.name.replace('~', "~~");
+                *count += 1;
+
+                if *count == 1 { name } else { format!("{}~{}", name, count) }
+            })
+            .collect()
+    }
+
+    /// Relative paths, with `/` separators, to extract each record to. Names are cleaned by `PAKRecord::safe_path`;
+    /// when two records end up with the same path (ignoring case) or a path runs through another record's file, the
+    /// later record gets a numbered path, such as `name~2`. Paths in `reserved` are never used.
+    pub fn extraction_paths(&self, reserved: &[&str]) -> Vec<String> {
+        let mut files = reserved.iter().map(|r| r.to_lowercase()).collect::<HashSet<String>>();
+        let mut dirs = HashSet::new();
+        let mut paths = Vec::with_capacity(self.records.len());
+
+        for record in self.records.iter() {
+            let safe = PAKRecord::safe_path(&record.name);
+            let components = safe.split('/').collect::<Vec<&str>>();
+            let ancestors = (1..components.len()).map(|n| components[..n].join("/").to_lowercase());
+            // A directory cannot also be a file, so such names are flattened into the output directory.
+            let base = if ancestors.clone().any(|a| files.contains(&a)) { components.join("_") } else { safe.clone() };
+
+            let path = (1..)
+                .map(|i| if i == 1 { base.clone() } else { format!("{}~{}", base, i) })
+                .find(|p| !files.contains(&p.to_lowercase()) && !dirs.contains(&p.to_lowercase()))
+                .unwrap();
+            let components = path.split('/').collect::<Vec<&str>>();
+
+            for n in 1..components.len() {
+                dirs.insert(components[..n].join("/").to_lowercase());
+            }
+
+            files.insert(path.to_lowercase());
+            paths.push(path);
+        }
+
+        paths
+    }
+
+    pub fn parse_json(s: &str) -> Result<PAKFile> {
+        serde_json::from_str(s).map_err(|x| Error::OtherError(Box::new(x)))
+    }
+
+    pub fn as_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|x| Error::OtherError(Box::new(x)))
+    }
+
+    pub fn parse_toml(s: &str) -> Result<PAKFile> {
+        toml::from_str(s).map_err(|x| Error::OtherError(Box::new(x)))
+    }
+
+    pub fn as_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|x| Error::OtherError(Box::new(x)))
+    }
+
+    /// Parses rows of record index, name, line index, text, encoding and shared block (see `as_csv`). Every row must
+    /// name the same encoding; a file without rows has no strings to encode and is UTF-8.
+    pub fn parse_csv(s: &str) -> Result<PAKFile> {
+        let mut reader = csv::Reader::from_reader(s.as_bytes());
+        let mut records: Vec<PAKRecord> = Vec::new();
+        let mut shared_blocks = Vec::new();
+        let mut encoding: Option<&'static Encoding> = None;
+
+        for (i, row) in reader.deserialize().enumerate() {
+            let row: PAKCSVRow = row.map_err(|x| Error::OtherError(Box::new(x)))?;
+            let error = |message: &str| Error::PAKFile(format!("CSV row {}: {}", i + 1, message));
+
+            match encoding {
+                Some(e) if e != row.encoding => {
+                    return Err(error(&format!("encoding {} differs from {}", row.encoding.name(), e.name())));
+                },
+                _ => encoding = Some(row.encoding),
+            }
+
+            if row.record == records.len() {
+                records.push(PAKRecord {
+                    name: row.name.clone(),
+                    lines: Vec::new(),
+                });
+                shared_blocks.push(row.block.unwrap_or(row.record));
+            } else if row.record + 1 != records.len() {
+                return Err(error(&format!("expected record {} or {}", records.len().saturating_sub(1), records.len())));
+            }
+
+            let record = records.last_mut().unwrap();
+
+            if row.name != record.name {
+                return Err(error(&format!("record {} is named {:?}, not {:?}", row.record, record.name, row.name)));
+            }
+
+            if row.block.unwrap_or(row.record) != shared_blocks[row.record] {
+                return Err(error(&format!("record {} is in block {}", row.record, shared_blocks[row.record])));
+            }
+
+            match row.line {
+                Some(line) if line == record.lines.len() => record.lines.push(row.text),
+                Some(line) => return Err(error(&format!("expected line {}, got {}", record.lines.len(), line))),
+                // A record without lines.
+                None if record.lines.is_empty() && row.text.is_empty() => (),
+                None => return Err(error("missing line index")),
+            }
+        }
+
+        let mut pak = PAKFile::new(encoding.unwrap_or(UTF_8), records);
+
+        if shared_blocks.iter().enumerate().any(|(i, &j)| i != j) {
+            pak.shared_blocks = shared_blocks;
+        }
+
+        Ok(pak)
+    }
+
+    /// Writes one row per line with the record index, record name, line index, text, the encoding of the file and the
+    /// record whose block the record shares, if any (see `shared_blocks`). A record without lines is a row without a
+    /// line index, and the record index keeps records that share a name apart.
+    pub fn as_csv(&self) -> Result<String> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+
+        for (i, record) in self.records.iter().enumerate() {
+            let rows = record.lines.iter()
+                .enumerate()
+                .map(|(j, line)| (Some(j), line.as_str()))
+                .chain(if record.lines.is_empty() { Some((None, "")) } else { None });
+
+            for (line, text) in rows {
+                writer.serialize(PAKCSVRow {
+                    record: i,
+                    name: record.name.clone(),
+                    line,
+                    text: text.into(),
+                    encoding: self.encoding,
+                    block: self.shared_blocks.get(i).cloned().filter(|&j| j != i),
+                }).map_err(|x| Error::OtherError(Box::new(x)))?;
+            }
+        }
+
+        let data = writer.into_inner().map_err(|x| Error::OtherError(Box::new(x.into_error())))?;
+
+        String::from_utf8(data).map_err(|x| Error::OtherError(Box::new(x)))
+    }
+
+    /// For each record, the index of the record whose block it is written in.
+    fn block_owners(&self, sharing: &PAKSharing) -> Vec<usize> {
+        let mut owners = Vec::with_capacity(self.records.len());
+        let mut first_records = HashMap::new();
+
+        for (i, record) in self.records.iter().enumerate() {
+            let owner = match *sharing {
+                PAKSharing::None => i,
+                PAKSharing::Identical => *first_records.entry(&record.lines).or_insert(i),
+                PAKSharing::Blocks(ref blocks) => match blocks.get(i) {
+                    Some(&j) if j < i && owners[j] == j && self.records[j].lines == record.lines => j,
+                    _ => i,
+                },
+            };
+
+            owners.push(owner);
+        }
+
+        owners
+    }
+
+    /// The records and, if any of them share a line block, the index of the first record at each block offset.
+    fn parse_raw(data: &[u8]) -> Result<(Vec<RawRecord>, Vec<usize>)> {
+        let table = Self::parse_name_table(data)?;
+        let mut first_records = HashMap::new();
+        let shared_blocks = table.iter()
+            .enumerate()
+            .map(|(i, &(_, offset))| *first_records.entry(offset).or_insert(i))
+            .collect::<Vec<usize>>();
+        let records = table.into_iter()
+            .map(|(name, offset)| Ok((name, Self::parse_block(data, offset)?)))
+            .collect::<Result<Vec<RawRecord>>>()?;
+
+        if shared_blocks.iter().enumerate().all(|(i, &j)| i == j) {
+            Ok((records, Vec::new()))
+        } else {
+            Ok((records, shared_blocks))
+        }
+    }
+
+    /// The names of the records and the offsets of their line blocks.
+    fn parse_name_table(data: &[u8]) -> Result<Vec<(Vec<u8>, usize)>> {
         let mut stream = io::Cursor::new(data);
         let records_len = stream.read_u32::<LE>()? as usize;
-        let mut records = Vec::with_capacity(records_len);
+        let mut records = Vec::with_capacity(records_len.min(data.len() / 8));
 
         for _ in 0..records_len {
-            // TODO: Is this just a name or a file path?
+            // Names may look like file paths, but nothing in the game says they are one. Anything that turns a name
+            // into a path treats it as untrusted input (see `PAKRecord::safe_path`).
             let name = Self::read_string(&mut stream)?;
             let offset = stream.read_u32::<LE>()? as usize;
 
@@ -29,75 +378,379 @@ impl PAKFile {
                 return Err(Error::PAKFile(format!("offset out of bounds: 0x{:X?}", offset)));
             }
 
-            let prev_pos = stream.position();
+            records.push((name, offset));
+        }
+
+        Ok(records)
+    }
 
-            stream.set_position(offset as u64);
+    fn parse_block(data: &[u8], offset: usize) -> Result<Vec<Vec<u8>>> {
+        let mut stream = io::Cursor::new(data);
 
-            let lines_len = stream.read_u32::<LE>()? as usize;
-            let mut lines = Vec::with_capacity(lines_len);
+        stream.set_position(offset as u64);
 
-            for _ in 0..lines_len {
-                lines.push(Self::read_string(&mut stream)?);
-            }
+        let lines_len = stream.read_u32::<LE>()? as usize;
+        let mut lines = Vec::with_capacity(lines_len.min(data.len() / 4));
+
+        for _ in 0..lines_len {
+            lines.push(Self::read_string(&mut stream)?);
+        }
 
-            stream.set_position(prev_pos);
+        Ok(lines)
+    }
+
+    fn detect_raw_encoding(records: &[RawRecord]) -> &'static Encoding {
+        Self::detect_strings(records.iter().flat_map(|(name, lines)| Some(name).into_iter().chain(lines.iter())))
+    }
+
+    fn detect_strings<'b, I: Iterator<Item = &'b Vec<u8>> + Clone>(strings: I) -> &'static Encoding {
+        if strings.clone().all(|s| std::str::from_utf8(s).is_ok()) {
+            return UTF_8;
+        }
 
-            records.push(PAKRecord {
-                name: name,
-                lines: lines,
+        let mut detector = EncodingDetector::new();
+
+        for s in strings {
+            detector.feed(s, false);
+            // Strings are separate texts, so do not let one end in the middle of a multi-byte character.
+            detector.feed(b"\n", false);
+        }
+
+        detector.feed(&[], true);
+        detector.guess(None, false)
+    }
+
+    fn decode_records(records: Vec<RawRecord>, shared_blocks: Vec<usize>, encoding: &'static Encoding)
+        -> Result<PAKFile> {
+        let decode = |s: &[u8]| Self::decode_string(s, encoding);
+        let mut decoded = Vec::with_capacity(records.len());
+
+        for (name, lines) in records {
+            decoded.push(PAKRecord {
+                name: decode(&name)?,
+                lines: lines.iter().map(|l| decode(l)).collect::<Result<Vec<String>>>()?,
             });
         }
 
-        Ok(PAKFile {
-            records: records,
-        })
+        let mut pak = PAKFile::new(encoding, decoded);
+
+        pak.shared_blocks = shared_blocks;
+
+        Ok(pak)
+    }
+
+    /// Decodes a string, making sure that encoding it again gives the same bytes. Some code pages have several byte
+    /// sequences for one character, such as the NEC and IBM extensions of Shift-JIS, which would be changed silently
+    /// when the file is written.
+    fn decode_string(s: &[u8], encoding: &'static Encoding) -> Result<String> {
+        let decoded = encoding.decode_without_bom_handling_and_without_replacement(s)
+            .ok_or_else(|| Error::PAKFile(format!("invalid {} string: {:X?}", encoding.name(), s)))?;
+        let (encoded, _, unmappable) = encoding.encode(&decoded);
+
+        if unmappable || encoded != s {
+            return Err(Error::PAKFile(format!("{} string does not encode back to the same bytes: {:X?}",
+                encoding.name(), s)));
+        }
+
+        Ok(decoded.into_owned())
+    }
+
+    fn encode_string(&self, s: &str) -> Result<Vec<u8>> {
+        let (bytes, _, unmappable) = self.encoding.encode(s);
+
+        if unmappable {
+            return Err(Error::PAKFile(format!("{:?} cannot be encoded in {}", s, self.encoding.name())));
+        }
+
+        Ok(bytes.into_owned())
     }
 
-    fn read_string(stream: &mut Read) -> Result<String> {
+    fn read_string(stream: &mut dyn Read) -> Result<Vec<u8>> {
         let len = stream.read_u32::<LE>()? as usize;
         let mut buf = vec![0u8; len];
         stream.read_exact(buf.as_mut_slice())?;
+        Ok(buf)
+    }
 
-        // TODO: Find proper encoding (likely an extended ASCII).
-        // Use UTF-8 for now (at least it works).
-        Ok(String::from_utf8_lossy(&buf).into())
+    fn write_string(stream: &mut dyn Write, s: &[u8]) -> Result<()> {
+        stream.write_u32::<LE>(s.len() as u32)?;
+        stream.write_all(s)?;
+        Ok(())
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct PAKCSVRow {
+    record: usize,
+    name: String,
+    line: Option<usize>,
+    text: String,
+    #[serde(serialize_with = "serialize_encoding", deserialize_with = "deserialize_encoding")]
+    encoding: &'static Encoding,
+    /// The record whose line block this record shares. Files written before the column existed do not have it.
+    #[serde(default)]
+    block: Option<usize>,
+}
+
+fn serialize_encoding<S: Serializer>(encoding: &&'static Encoding, serializer: S)
+    -> ::std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(encoding.name())
+}
+
+fn deserialize_encoding<'de, D: Deserializer<'de>>(deserializer: D)
+    -> ::std::result::Result<&'static Encoding, D::Error> {
+    let label = String::deserialize(deserializer)?;
+    PAKFile::encoding_for_label(&label).map_err(D::Error::custom)
+}
+
 impl PAKRecord {
 
     pub fn as_single_string(&self) -> String {
         self.lines.join("\n")
     }
+
+    /// Turns a record name into a relative path that stays inside the output directory on any common file system.
+    /// Both `/` and `\\` separate directories. Empty components are dropped, so absolute paths become relative;
+    /// characters that Windows does not allow become `_`, as do components made of dots only (`..`) and trailing dots
+    /// and spaces, and reserved device names such as `CON` get a `_` prefix.
+    pub fn safe_path(name: &str) -> String {
+        let components = name.split(['/', '\\'])
+            .filter(|c| !c.is_empty())
+            .map(|component| {
+                let mut safe = component.chars()
+                    .map(|c| if c.is_control() || "<>:\"|?*".contains(c) { '_' } else { c })
+                    .collect::<String>();
+
+                if safe.chars().all(|c| c == '.') {
+                    safe = "_".repeat(safe.len());
+                }
+
+                let trimmed = safe.trim_end_matches(['.', ' ']).len();
+                let trailing = safe.len() - trimmed;
+                safe.truncate(trimmed);
+                safe.push_str(&"_".repeat(trailing));
+
+                let stem = safe.split('.').next().unwrap().to_uppercase();
+                let reserved = ["CON", "PRN", "AUX", "NUL"].contains(&stem.as_str())
+                    || ((stem.starts_with("COM") || stem.starts_with("LPT")) && stem.len() == 4
+                        && stem.as_bytes()[3].is_ascii_digit());
+
+                if reserved {
+                    safe.insert(0, '_');
+                }
+
+                safe
+            })
+            .collect::<Vec<String>>();
+
+        if components.is_empty() {
+            return "_".into();
+        }
+
+        components.join("/")
+    }
+
+    /// Whether `path` is relative and has no `..` or empty components, as the paths from `safe_path` are.
+    pub fn is_safe_path(path: &str) -> bool {
+        !path.is_empty() && path.split('/').all(|c| !c.is_empty() && c != "." && c != ".." && !c.contains('\\')
+            && !c.contains(':'))
+    }
+
+    /// Writes every line escaped (see `escape`) and followed by a newline, so that the text can be turned back into
+    /// the same lines by `from_escaped_text`. A record without lines is an empty text.
+    pub fn as_escaped_text(&self) -> String {
+        self.lines.iter()
+            .map(|l| Self::escape(l) + "\n")
+            .collect()
+    }
+
+    /// The reverse of `as_escaped_text`. Both `\n` and `\r\n` end a line, and the last newline may be missing.
+    pub fn from_escaped_text(name: &str, text: &str) -> Result<PAKRecord> {
+        Ok(PAKRecord {
+            name: name.into(),
+            lines: text.lines().map(Self::unescape).collect::<Result<Vec<String>>>()?,
+        })
+    }
+
+    /// Escapes backslashes and control characters, so that the result fits on one line and has no invisible
+    /// characters. Tab, line feed, carriage return and NUL become `\t`, `\n`, `\r` and `\0`; other control characters
+    /// become `\xNN`.
+    pub fn escape(s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+
+        for c in s.chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\0' => escaped.push_str("\\0"),
+                '\x01'..='\x1F' | '\x7F' => escaped.push_str(&format!("\\x{:02X}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+
+        escaped
+    }
+
+    pub fn unescape(s: &str) -> Result<String> {
+        let mut unescaped = String::with_capacity(s.len());
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unescaped.push(c);
+                continue;
+            }
+
+            unescaped.push(match chars.next() {
+                Some('\\') => '\\',
+                Some('t') => '\t',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('x') => {
+                    let hex = chars.by_ref().take(2).collect::<String>();
+
+                    match u8::from_str_radix(&hex, 16) {
+                        Ok(b) if hex.len() == 2 && b < 0x80 => b as char,
+                        _ => return Err(Error::PAKFile(format!("invalid escape \\x{} in {:?}", hex, s))),
+                    }
+                },
+                Some(c) => return Err(Error::PAKFile(format!("invalid escape \\{} in {:?}", c, s))),
+                None => return Err(Error::PAKFile(format!("unterminated escape in {:?}", s))),
+            });
+        }
+
+        Ok(unescaped)
+    }
+}
+
+/// Reads the name table of a PAK file up front and decodes the lines of a record only when asked for them, which
+/// suits looking up a few strings in a large file.
+pub struct PAKReader<'a> {
+    data: &'a [u8],
+    encoding: &'static Encoding,
+    names: Vec<String>,
+    offsets: Vec<usize>,
+    /// Index of the first record with each name.
+    index: HashMap<String, usize>,
+}
+
+impl<'a> PAKReader<'a> {
+
+    /// Reads the name table, guessing the encoding from the names as `PAKFile::detect_encoding` does for the whole
+    /// file. Names in plain ASCII read the same in every code page, so then the encoding is guessed from the whole
+    /// file, lines included. Use `with_encoding` when the encoding is known.
+    pub fn new(data: &'a [u8]) -> Result<PAKReader<'a>> {
+        let table = PAKFile::parse_name_table(data)?;
+        let encoding = if table.iter().all(|(name, _)| name.is_ascii()) {
+            PAKFile::detect_encoding(data)?
+        } else {
+            PAKFile::detect_strings(table.iter().map(|(name, _)| name))
+        };
+
+        Self::from_name_table(data, table, encoding)
+    }
+
+    /// Reads the name table of a PAK file whose strings are in `encoding`.
+    pub fn with_encoding(data: &'a [u8], encoding: &'static Encoding) -> Result<PAKReader<'a>> {
+        Self::from_name_table(data, PAKFile::parse_name_table(data)?, encoding)
+    }
+
+    fn from_name_table(data: &'a [u8], table: Vec<(Vec<u8>, usize)>, encoding: &'static Encoding)
+        -> Result<PAKReader<'a>> {
+        let mut names = Vec::with_capacity(table.len());
+        let mut offsets = Vec::with_capacity(table.len());
+        let mut index = HashMap::with_capacity(table.len());
+
+        for (i, (name, offset)) in table.into_iter().enumerate() {
+            let name = PAKFile::decode_string(&name, encoding)?;
+
+            index.entry(name.clone()).or_insert(i);
+            names.push(name);
+            offsets.push(offset);
+        }
+
+        Ok(PAKReader {
+            data,
+            encoding,
+            names,
+            offsets,
+            index,
+        })
+    }
+
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    /// The record names in file order.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Index of the first record named `name`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.index.get(name).cloned()
+    }
+
+    /// Decodes the lines of the record at `index`.
+    pub fn lines(&self, index: usize) -> Result<Vec<String>> {
+        let offset = *self.offsets.get(index)
+            .ok_or_else(|| Error::PAKFile(format!("record index out of bounds: {}", index)))?;
+
+        PAKFile::parse_block(self.data, offset)?.iter()
+            .map(|l| PAKFile::decode_string(l, self.encoding))
+            .collect()
+    }
+
+    /// Decodes the first record named `name`, if there is one.
+    pub fn get(&self, name: &str) -> Result<Option<PAKRecord>> {
+        match self.position(name) {
+            Some(index) => Ok(Some(PAKRecord {
+                name: name.into(),
+                lines: self.lines(index)?,
+            })),
+            None => Ok(None),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use encoding_rs;
 
     lazy_static! {
-        static ref DATA_0_STRUCT: PAKFile = PAKFile {
-            records: vec![
-                PAKRecord {
-                    name: "Hello there".into(),
-                    lines: vec![
-                        "General Kenobi!".into(),
-                    ],
-                },
-                PAKRecord {
-                    name: "Rewrite it in Rust".into(),
-                    lines: vec![],
-                },
-                PAKRecord {
-                    name: "se tonight".into(),
-                    lines: vec![
-                        "party rockers in the hou".into(),
-                        "everybody just ha".into(),
-                        "ve a good time".into(),
-                    ],
-                },
-            ]
-        };
+        static ref DATA_0_STRUCT: PAKFile = PAKFile::new(UTF_8, vec![
+            PAKRecord {
+                name: "Hello there".into(),
+                lines: vec![
+                    "General Kenobi!".into(),
+                ],
+            },
+            PAKRecord {
+                name: "Rewrite it in Rust".into(),
+                lines: vec![],
+            },
+            PAKRecord {
+                name: "se tonight".into(),
+                lines: vec![
+                    "party rockers in the hou".into(),
+                    "everybody just ha".into(),
+                    "ve a good time".into(),
+                ],
+            },
+        ]);
     }
 
     const DATA_0: &[u8] = b"\
@@ -134,6 +787,106 @@ mod tests {
         assert_eq!(PAKFile::parse(DATA_0).unwrap(), *DATA_0_STRUCT);
     }
 
+    #[test]
+    fn as_vec() {
+        let data = DATA_0_STRUCT.as_vec().unwrap();
+
+        assert_eq!(data, DATA_0);
+        assert_eq!(PAKFile::parse(&data).unwrap(), *DATA_0_STRUCT);
+    }
+
+    #[test]
+    fn sharing() {
+        let mut pak = PAKFile::new(UTF_8, vec![
+            PAKRecord {
+                name: "a".into(),
+                lines: vec!["Yes".into(), "No".into()],
+            },
+            PAKRecord {
+                name: "b".into(),
+                lines: vec![],
+            },
+            PAKRecord {
+                name: "c".into(),
+                lines: vec!["Yes".into(), "No".into()],
+            },
+            PAKRecord {
+                name: "d".into(),
+                lines: vec![],
+            },
+        ]);
+
+        let shared_blocks = |data: &[u8]| PAKFile::parse(data).unwrap().shared_blocks;
+        let data = pak.as_vec_with_sharing(&PAKSharing::Identical).unwrap();
+
+        assert_eq!(data.len(), 4 + 4 * 9 + (4 + 4 + 3 + 4 + 2) + 4);
+        assert_eq!(shared_blocks(&data), vec![0, 1, 0, 1]);
+        assert!(shared_blocks(&pak.as_vec().unwrap()).is_empty());
+        assert_eq!(shared_blocks(&pak.as_vec_with_sharing(&PAKSharing::Blocks(vec![0, 1, 0])).unwrap()),
+            vec![0, 1, 0, 3]);
+
+        // Parsing keeps the sharing, so writing the file again gives the same bytes.
+        pak.shared_blocks = vec![0, 1, 0, 1];
+
+        assert_eq!(PAKFile::parse(&data).unwrap(), pak);
+        assert_eq!(pak.as_vec().unwrap(), data);
+
+        pak.records[0].lines[1] = "Maybe".into();
+
+        let data = pak.as_vec().unwrap();
+
+        pak.shared_blocks = vec![0, 1, 2, 1];
+
+        assert_eq!(PAKFile::parse(&data).unwrap(), pak);
+    }
+
+    #[test]
+    fn get() {
+        assert_eq!(DATA_0_STRUCT.get("se tonight"), Some(&DATA_0_STRUCT.records[2]));
+        assert_eq!(DATA_0_STRUCT.get("Se tonight"), None);
+
+        let mut pak = localized(UTF_8, &[]);
+
+        assert_eq!(pak.get("text").unwrap().name, "text");
+        pak.records[0].name = "renamed".into();
+        assert_eq!(pak.get("text"), None);
+        assert_eq!(pak.get("renamed").unwrap().name, "renamed");
+        pak.records.push(PAKRecord {
+            name: "renamed".into(),
+            lines: vec![],
+        });
+        assert_eq!(pak.index(), [("renamed", 0)].iter().cloned().collect());
+
+        let reader = PAKReader::new(DATA_0).unwrap();
+
+        assert_eq!(reader.encoding(), UTF_8);
+        assert_eq!(reader.len(), 3);
+        assert_eq!(reader.names(), ["Hello there", "Rewrite it in Rust", "se tonight"]);
+        assert_eq!(reader.position("Rewrite it in Rust"), Some(1));
+        assert_eq!(reader.get("se tonight").unwrap().as_ref(), Some(&DATA_0_STRUCT.records[2]));
+        assert_eq!(reader.get("Rewrite it in Rust").unwrap().as_ref(), Some(&DATA_0_STRUCT.records[1]));
+        assert_eq!(reader.get("General Kenobi!").unwrap(), None);
+        assert!(reader.lines(3).is_err());
+
+        let pak = localized(encoding_rs::WINDOWS_1252, &["Café", "Crème"]);
+        let data = pak.as_vec().unwrap();
+        let reader = PAKReader::with_encoding(&data, encoding_rs::WINDOWS_1252).unwrap();
+
+        assert_eq!(reader.lines(0).unwrap(), pak.records[0].lines);
+        assert!(PAKReader::with_encoding(&data, UTF_8).unwrap().lines(0).is_err());
+
+        // The names are plain ASCII, so the encoding comes from the lines.
+        let pak = localized(encoding_rs::WINDOWS_1252, &[
+            "Le maire a décidé de construire une école près de la rivière.",
+            "Les résidents sont très mécontents à cause des impôts élevés.",
+        ]);
+        let data = pak.as_vec().unwrap();
+        let reader = PAKReader::new(&data).unwrap();
+
+        assert_eq!(reader.encoding(), encoding_rs::WINDOWS_1252);
+        assert_eq!(reader.get("text").unwrap().as_ref(), Some(&pak.records[0]));
+    }
+
     #[test]
     fn as_single_string() {
         assert_eq!(DATA_0_STRUCT.records[0].as_single_string(), "General Kenobi!");
@@ -141,4 +894,259 @@ mod tests {
         assert_eq!(DATA_0_STRUCT.records[2].as_single_string(), "party rockers in the hou\neverybody just ha\n\
             ve a good time");
     }
+
+    #[test]
+    fn escaped_text() {
+        let record = PAKRecord {
+            name: "tricky".into(),
+            lines: vec![
+                "two\nlines".into(),
+                "".into(),
+                "C:\\SimCity\\ \t\r\0\x1B\x7F trailing ".into(),
+                "".into(),
+            ],
+        };
+        let text = record.as_escaped_text();
+
+        assert_eq!(text, "two\\nlines\n\nC:\\\\SimCity\\\\ \\t\\r\\0\\x1B\\x7F trailing \n\n");
+        assert_eq!(PAKRecord::from_escaped_text("tricky", &text).unwrap(), record);
+        assert_eq!(PAKRecord::from_escaped_text("tricky", &text.replace('\n', "\r\n")).unwrap(), record);
+
+        for lines in [vec![], vec!["".into()], vec!["a".into()]].iter() {
+            let record = PAKRecord {
+                name: "".into(),
+                lines: lines.clone(),
+            };
+
+            assert_eq!(PAKRecord::from_escaped_text("", &record.as_escaped_text()).unwrap(), record);
+        }
+
+        assert_eq!(PAKRecord::from_escaped_text("", "a\nb").unwrap().lines, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn unescape_invalid() {
+        for s in ["\\", "\\q", "\\x4", "\\xZZ", "\\xFF"].iter() {
+            assert!(PAKRecord::unescape(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn safe_path() {
+        let names = [
+            ("text", "text"),
+            ("menus/file.txt", "menus/file.txt"),
+            ("menus\\file.txt", "menus/file.txt"),
+            ("../../etc/passwd", "__/__/etc/passwd"),
+            ("/absolute//path/", "absolute/path"),
+            ("C:\\Windows\\x", "C_/Windows/x"),
+            ("what?<*>|\"", "what______"),
+            ("tab\there\n", "tab_here_"),
+            ("trailing. ", "trailing__"),
+            ("con", "_con"),
+            ("Com1.txt", "_Com1.txt"),
+            ("COM10", "COM10"),
+            ("", "_"),
+            ("//", "_"),
+            (".", "_"),
+        ];
+
+        for &(name, path) in names.iter() {
+            assert_eq!(PAKRecord::safe_path(name), path, "{:?}", name);
+            assert!(PAKRecord::is_safe_path(path), "{:?}", path);
+        }
+
+        for path in ["", "/a", "a//b", "../a", "a/./b", "C:/a", "a\\b"].iter() {
+            assert!(!PAKRecord::is_safe_path(path), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn extraction_paths() {
+        let names = ["a", "A", "a", "a/b", "dir/x", "dir", "dir/x", "a~2/c", "a~2", "Index"];
+        let pak = PAKFile::new(UTF_8, names.iter().map(|&name| PAKRecord {
+            name: name.into(),
+            lines: vec![],
+        }).collect());
+
+        assert_eq!(pak.extraction_paths(&["index"]), vec!["a", "A~2", "a~3", "a_b", "dir/x", "dir~2", "dir/x~2",
+            "a~2_c", "a~2~2", "Index~2"]);
+    }
+
+    fn tricky() -> PAKFile {
+        let mut pak = PAKFile::new(encoding_rs::WINDOWS_1252, vec![
+            PAKRecord {
+                name: "same".into(),
+                lines: vec!["two\nlines, \"quoted\"".into(), "".into(), "  spaces  ".into(), "".into()],
+            },
+            PAKRecord {
+                name: "same".into(),
+                lines: vec![],
+            },
+            PAKRecord {
+                name: "".into(),
+                lines: vec!["".into()],
+            },
+            PAKRecord {
+                name: "same".into(),
+                lines: vec!["C:\\path\t\r\0".into()],
+            },
+            PAKRecord {
+                name: "copy".into(),
+                lines: vec!["".into()],
+            },
+        ]);
+
+        pak.shared_blocks = vec![0, 1, 2, 3, 2];
+        pak
+    }
+
+    #[test]
+    fn json_toml() {
+        let pak = tricky();
+
+        assert_eq!(PAKFile::parse_json(&pak.as_json().unwrap()).unwrap(), pak);
+        assert_eq!(PAKFile::parse_toml(&pak.as_toml().unwrap()).unwrap(), pak);
+        assert_eq!(PAKFile::parse_json(&DATA_0_STRUCT.as_json().unwrap()).unwrap(), *DATA_0_STRUCT);
+        assert_eq!(PAKFile::parse_toml(&DATA_0_STRUCT.as_toml().unwrap()).unwrap(), *DATA_0_STRUCT);
+        assert!(pak.as_json().unwrap().contains("\"encoding\": \"windows-1252\""));
+        assert!(!DATA_0_STRUCT.as_json().unwrap().contains("shared_blocks"));
+        assert_eq!(PAKFile::parse_with_encoding(&pak.as_vec().unwrap(), pak.encoding).unwrap(), pak);
+        assert!(PAKFile::parse_json("{\"encoding\": \"nope\", \"records\": []}").is_err());
+    }
+
+    #[test]
+    fn csv() {
+        let pak = tricky();
+        let text = pak.as_csv().unwrap();
+
+        assert!(text.starts_with("record,name,line,text,encoding,block\n\
+            0,same,0,\"two\nlines, \"\"quoted\"\"\",windows-1252,\n0,same,1,,windows-1252,\n"));
+        assert!(text.contains("\n1,same,,,windows-1252,\n2,,0,,windows-1252,\n3,same,0,"));
+        assert!(text.ends_with("\n4,copy,0,,windows-1252,2\n"));
+        assert_eq!(PAKFile::parse_csv(&text).unwrap(), pak);
+        assert_eq!(PAKFile::parse_csv("").unwrap().encoding, UTF_8);
+        // Without the block column, every record has a block of its own.
+        assert_eq!(PAKFile::parse_csv("record,name,line,text,encoding\n0,a,0,x,UTF-8\n1,b,0,x,UTF-8\n").unwrap(),
+            PAKFile::new(UTF_8, vec![
+                PAKRecord {
+                    name: "a".into(),
+                    lines: vec!["x".into()],
+                },
+                PAKRecord {
+                    name: "b".into(),
+                    lines: vec!["x".into()],
+                },
+            ]));
+
+        let invalid = [
+            "record,name,line,text,encoding\n1,a,0,x,UTF-8\n",
+            "record,name,line,text,encoding\n0,a,1,x,UTF-8\n",
+            "record,name,line,text,encoding\n0,a,0,x,UTF-8\n0,b,1,y,UTF-8\n",
+            "record,name,line,text,encoding\n0,a,,x,UTF-8\n",
+            "record,name,line,text,encoding\n0,a,0,x,UTF-8\n0,a,,,UTF-8\n",
+            "record,name,line,text,encoding\n0,a,0,x,UTF-8\n0,a,1,y,windows-1252\n",
+            "record,name,line,text,encoding\n0,a,0,x,nope\n",
+            "record,name,line,text\n0,a,0,x\n",
+            "record,name,line,text,encoding,block\n0,a,0,x,UTF-8,\n1,b,0,x,UTF-8,0\n1,b,1,y,UTF-8,\n",
+        ];
+
+        for text in invalid.iter() {
+            assert!(PAKFile::parse_csv(text).is_err(), "{:?}", text);
+        }
+    }
+
+    fn localized(encoding: &'static Encoding, lines: &[&str]) -> PAKFile {
+        PAKFile::new(encoding, vec![
+            PAKRecord {
+                name: "text".into(),
+                lines: lines.iter().map(|&l| l.into()).collect(),
+            },
+        ])
+    }
+
+    #[test]
+    fn encodings() {
+        let files = [
+            localized(encoding_rs::WINDOWS_1252, &[
+                "Le maire a décidé de construire une école près de la rivière.",
+                "Les résidents sont très mécontents à cause des impôts élevés.",
+            ]),
+            localized(encoding_rs::WINDOWS_874, &[
+                "นายกเทศมนตรีตัดสินใจสร้างโรงเรียนใกล้แม่น้ำ",
+                "ประชาชนไม่พอใจเพราะภาษีสูงเกินไป",
+            ]),
+            localized(encoding_rs::WINDOWS_1250, &[
+                "Starosta se rozhodl postavit školu u řeky.",
+                "Obyvatelé jsou nespokojení kvůli vysokým daním a špatným silnicím.",
+            ]),
+            localized(encoding_rs::SHIFT_JIS, &[
+                "市長は川の近くに学校を建てることにしました。",
+                "税金が高すぎるので、住民は不満です。",
+            ]),
+        ];
+
+        for pak in files.iter() {
+            let data = pak.as_vec().unwrap();
+
+            assert_eq!(PAKFile::detect_encoding(&data).unwrap(), pak.encoding);
+            assert_eq!(PAKFile::parse(&data).unwrap(), *pak);
+            assert_eq!(PAKFile::parse(&data).unwrap().as_vec().unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn encode_unmappable() {
+        assert!(localized(encoding_rs::WINDOWS_1252, &["ภาษี"]).as_vec().is_err());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let data = b"\x01\x00\x00\x00\x02\x00\x00\x00\x81\x20\x0E\x00\x00\x00\x00\x00\x00\x00";
+
+        assert!(PAKFile::parse_with_encoding(data, encoding_rs::WINDOWS_1252).is_ok());
+        assert!(PAKFile::parse_with_encoding(data, encoding_rs::SHIFT_JIS).is_err());
+    }
+
+    #[test]
+    fn unique_names() {
+        let pak = PAKFile::new(UTF_8, ["news", "news~2", "news", "a~", "a~"].iter()
+            .map(|&name| PAKRecord {
+                name: name.into(),
+                lines: vec![],
+            })
+            .collect());
+
+        assert_eq!(pak.unique_names(), vec!["news", "news~~2", "news~2", "a~~", "a~~~2"]);
+    }
+
+    #[test]
+    fn decode_not_reversible() {
+        // 0xED40 is an NEC-selected IBM extension, which encodes as the IBM extension 0xFA5C.
+        let data = b"\x01\x00\x00\x00\x02\x00\x00\x00\xED\x40\x0E\x00\x00\x00\x00\x00\x00\x00";
+        let data_ibm = b"\x01\x00\x00\x00\x02\x00\x00\x00\xFA\x5C\x0E\x00\x00\x00\x00\x00\x00\x00";
+
+        assert!(PAKFile::parse_with_encoding(data, encoding_rs::SHIFT_JIS).is_err());
+        assert!(PAKFile::parse_with_encoding(data_ibm, encoding_rs::SHIFT_JIS).is_ok());
+        assert!(PAKReader::with_encoding(data, encoding_rs::SHIFT_JIS).is_err());
+    }
+
+    #[test]
+    fn is_ascii() {
+        assert!(DATA_0_STRUCT.is_ascii());
+        assert!(!PAKFile::new(UTF_8, vec![PAKRecord {
+            name: "a".into(),
+            lines: vec!["école".into()],
+        }]).is_ascii());
+    }
+
+    #[test]
+    fn encoding_for_label() {
+        assert_eq!(PAKFile::encoding_for_label("CP874").unwrap(), encoding_rs::WINDOWS_874);
+        assert_eq!(PAKFile::encoding_for_label("tis-620").unwrap(), encoding_rs::WINDOWS_874);
+        assert_eq!(PAKFile::encoding_for_label("cp1250").unwrap(), encoding_rs::WINDOWS_1250);
+        assert_eq!(PAKFile::encoding_for_label("Shift_JIS").unwrap(), encoding_rs::SHIFT_JIS);
+        assert!(PAKFile::encoding_for_label("utf-16le").is_err());
+        assert!(PAKFile::encoding_for_label("cp99999").is_err());
+    }
 }
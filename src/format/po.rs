@@ -0,0 +1,481 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use error::*;
+use format::PAKFile;
+
+const PO_HEADER: &str = "MIME-Version: 1.0\nContent-Type: text/plain; charset=UTF-8\nContent-Transfer-Encoding: 8bit\n";
+
+/// A gettext translation catalog. Only UTF-8 catalogs are supported.
+#[derive(Debug, PartialEq)]
+pub struct POFile {
+    /// The translation of the empty message, which holds the catalog metadata.
+    pub header: String,
+    pub entries: Vec<POEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct POEntry {
+    pub msgctxt: Option<String>,
+    pub msgid: String,
+    /// The translation, or empty if there is none yet.
+    pub msgstr: String,
+    pub fuzzy: bool,
+}
+
+/// What happened to each PAK line when merging a catalog, identified by its `msgctxt`.
+#[derive(Debug, Default, PartialEq)]
+pub struct POMergeReport {
+    pub translated: usize,
+    /// Lines whose translation is marked fuzzy. They are only merged if asked to.
+    pub fuzzy: Vec<String>,
+    /// Lines without a translation, including lines missing from the catalog.
+    pub untranslated: Vec<String>,
+    /// Lines whose source text differs from the `msgid` in the catalog, so the translation is out of date.
+    pub changed: Vec<String>,
+    /// Catalog entries that match no line.
+    pub unknown: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum POField {
+    Context,
+    Id,
+    Translation,
+    Ignored,
+}
+
+impl POFile {
+
+    /// Makes a catalog with one untranslated entry per non-empty line. The `msgctxt` of a line is the record name and
+    /// the line index, as in `name:0`, with the name made unique by `PAKFile::unique_names` (`name~2:0`).
+    pub fn from_pak(pak: &PAKFile) -> POFile {
+        let entries = Self::contexts(pak).into_iter()
+            .map(|(record, line, msgctxt)| POEntry {
+                msgctxt: Some(msgctxt),
+                msgid: pak.records[record].lines[line].clone(),
+                msgstr: String::new(),
+                fuzzy: false,
+            })
+            .collect();
+
+        POFile {
+            header: PO_HEADER.into(),
+            entries,
+        }
+    }
+
+    /// Replaces the lines of `pak` with their translations. Fuzzy translations are skipped unless `use_fuzzy` is set.
+    pub fn merge_into(&self, pak: &mut PAKFile, use_fuzzy: bool) -> POMergeReport {
+        let entries = self.entries.iter()
+            .filter_map(|e| e.msgctxt.as_ref().map(|c| (c.as_str(), e)))
+            .collect::<HashMap<&str, &POEntry>>();
+        let mut used = HashSet::new();
+        let mut report = POMergeReport::default();
+
+        for (record, line, msgctxt) in Self::contexts(pak) {
+            let text = &mut pak.records[record].lines[line];
+
+            match entries.get(msgctxt.as_str()) {
+                Some(entry) => {
+                    used.insert(entry.msgctxt.as_ref().unwrap().as_str());
+
+                    if entry.msgid != *text {
+                        report.changed.push(msgctxt);
+                    } else if entry.msgstr.is_empty() {
+                        report.untranslated.push(msgctxt);
+                    } else if entry.fuzzy && !use_fuzzy {
+                        report.fuzzy.push(msgctxt);
+                    } else {
+                        if entry.fuzzy {
+                            report.fuzzy.push(msgctxt);
+                        }
+
+                        *text = entry.msgstr.clone();
+                        report.translated += 1;
+                    }
+                },
+                None => report.untranslated.push(msgctxt),
+            }
+        }
+
+        report.unknown = self.entries.iter()
+            .filter_map(|e| e.msgctxt.as_ref())
+            .filter(|c| !used.contains(c.as_str()))
+            .cloned()
+            .collect();
+
+        report
+    }
+
+    /// The `msgctxt` of every non-empty line, with the record and line index.
+    fn contexts(pak: &PAKFile) -> Vec<(usize, usize, String)> {
+        let mut contexts = Vec::new();
+
+        for (i, (record, name)) in pak.records.iter().zip(pak.unique_names()).enumerate() {
+            for (j, line) in record.lines.iter().enumerate() {
+                // An empty msgid is reserved for the header, and there is nothing to translate anyway.
+                if !line.is_empty() {
+                    contexts.push((i, j, format!("{}:{}", name, j)));
+                }
+            }
+        }
+
+        contexts
+    }
+
+    pub fn parse(text: &str) -> Result<POFile> {
+        let mut header = None;
+        let mut entries = Vec::new();
+        let mut entry = POEntry {
+            msgctxt: None,
+            msgid: String::new(),
+            msgstr: String::new(),
+            fuzzy: false,
+        };
+        let mut fields = Vec::new();
+        let mut current = None;
+
+        for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            let error = |message: &str| Error::POFile(format!("line {}: {}", i, message));
+
+            if line.is_empty() || line.starts_with('#') {
+                // Comments and blank lines end an entry.
+                if fields.contains(&POField::Translation) {
+                    Self::push_entry(&mut header, &mut entries, &mut entry, &mut fields);
+                }
+
+                if line.starts_with("#,") && line[2..].split(',').any(|f| f.trim() == "fuzzy") {
+                    entry.fuzzy = true;
+                }
+
+                current = None;
+                continue;
+            }
+
+            let (keyword, rest) = match line.find(|c: char| c.is_whitespace()) {
+                Some(pos) if !line.starts_with('"') => (&line[..pos], line[pos..].trim_start()),
+                _ => ("", line),
+            };
+            let value = Self::unquote(rest).map_err(|e| error(&e))?;
+            let field = match keyword {
+                "" => current.ok_or_else(|| error("string without a keyword"))?,
+                "msgctxt" => POField::Context,
+                "msgid" => POField::Id,
+                "msgstr" | "msgstr[0]" => POField::Translation,
+                "msgid_plural" => POField::Ignored,
+                k if k.starts_with("msgstr[") => POField::Ignored,
+                k => return Err(error(&format!("unknown keyword {}", k))),
+            };
+
+            // A new msgctxt or msgid after a translation starts the next entry.
+            if !keyword.is_empty() && matches!(field, POField::Context | POField::Id)
+                && fields.contains(&POField::Translation) {
+                Self::push_entry(&mut header, &mut entries, &mut entry, &mut fields);
+            }
+
+            if !keyword.is_empty() {
+                if fields.contains(&field) && field != POField::Ignored {
+                    return Err(error(&format!("duplicate {}", keyword)));
+                }
+
+                fields.push(field);
+            }
+
+            match field {
+                POField::Context => entry.msgctxt.get_or_insert_with(String::new).push_str(&value),
+                POField::Id => entry.msgid.push_str(&value),
+                POField::Translation => entry.msgstr.push_str(&value),
+                POField::Ignored => (),
+            }
+
+            current = Some(field);
+        }
+
+        if !fields.is_empty() {
+            if !fields.contains(&POField::Translation) {
+                return Err(Error::POFile("last entry has no msgstr".into()));
+            }
+
+            Self::push_entry(&mut header, &mut entries, &mut entry, &mut fields);
+        }
+
+        Ok(POFile {
+            header: header.unwrap_or_default(),
+            entries,
+        })
+    }
+
+    fn push_entry(header: &mut Option<String>, entries: &mut Vec<POEntry>, entry: &mut POEntry,
+        fields: &mut Vec<POField>) {
+        let done = ::std::mem::replace(entry, POEntry {
+            msgctxt: None,
+            msgid: String::new(),
+            msgstr: String::new(),
+            fuzzy: false,
+        });
+
+        if done.msgctxt.is_none() && done.msgid.is_empty() && header.is_none() {
+            *header = Some(done.msgstr);
+        } else {
+            entries.push(done);
+        }
+
+        fields.clear();
+    }
+
+    pub fn as_string(&self) -> String {
+        let mut output = String::new();
+
+        output.push_str("msgid \"\"\n");
+        Self::write_field(&mut output, "msgstr", &self.header);
+
+        for entry in self.entries.iter() {
+            output.push('\n');
+
+            if entry.fuzzy {
+                output.push_str("#, fuzzy\n");
+            }
+
+            if let Some(ref msgctxt) = entry.msgctxt {
+                Self::write_field(&mut output, "msgctxt", msgctxt);
+            }
+
+            Self::write_field(&mut output, "msgid", &entry.msgid);
+            Self::write_field(&mut output, "msgstr", &entry.msgstr);
+        }
+
+        output
+    }
+
+    /// Writes a string on one line, or split after each newline if it has more than one line.
+    fn write_field(output: &mut String, keyword: &str, value: &str) {
+        if value.trim_end_matches('\n').contains('\n') {
+            writeln!(output, "{} \"\"", keyword).unwrap();
+
+            for part in value.split_inclusive('\n') {
+                writeln!(output, "\"{}\"", Self::escape(part)).unwrap();
+            }
+        } else {
+            writeln!(output, "{} \"{}\"", keyword, Self::escape(value)).unwrap();
+        }
+    }
+
+    fn escape(s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+
+        for c in s.chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '"' => escaped.push_str("\\\""),
+                '\n' => escaped.push_str("\\n"),
+                '\t' => escaped.push_str("\\t"),
+                '\r' => escaped.push_str("\\r"),
+                c if c.is_ascii_control() => escaped.push_str(&format!("\\{:03o}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+
+        escaped
+    }
+
+    /// Parses a C string literal as used in PO files.
+    fn unquote(s: &str) -> ::std::result::Result<String, String> {
+        let inner = s.strip_prefix('"')
+            .and_then(|s| s.strip_suffix('"'))
+            .filter(|_| s.len() >= 2)
+            .ok_or_else(|| format!("expected a quoted string: {}", s))?;
+        let mut bytes = Vec::with_capacity(inner.len());
+        let mut chars = inner.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Err(format!("unescaped quote in {}", s)),
+                '\\' => match chars.next() {
+                    Some('n') => bytes.push(b'\n'),
+                    Some('t') => bytes.push(b'\t'),
+                    Some('r') => bytes.push(b'\r'),
+                    Some('a') => bytes.push(0x07),
+                    Some('b') => bytes.push(0x08),
+                    Some('f') => bytes.push(0x0C),
+                    Some('v') => bytes.push(0x0B),
+                    Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') | Some(c @ '?') => bytes.push(c as u8),
+                    Some(c @ '0'..='7') => {
+                        let mut value = c.to_digit(8).unwrap();
+
+                        for _ in 0..2 {
+                            match chars.peek().and_then(|c| c.to_digit(8)) {
+                                Some(d) => {
+                                    value = value * 8 + d;
+                                    chars.next();
+                                },
+                                None => break,
+                            }
+                        }
+
+                        bytes.push(value as u8);
+                    },
+                    Some('x') => {
+                        let mut value = 0;
+                        let mut digits = 0;
+
+                        while let Some(d) = chars.peek().and_then(|c| c.to_digit(16)) {
+                            value = value * 16 + d;
+                            digits += 1;
+                            chars.next();
+                        }
+
+                        if digits == 0 || value > 0xFF {
+                            return Err(format!("invalid hexadecimal escape in {}", s));
+                        }
+
+                        bytes.push(value as u8);
+                    },
+                    Some(c) => return Err(format!("invalid escape \\{} in {}", c, s)),
+                    None => return Err(format!("unterminated escape in {}", s)),
+                },
+                c => {
+                    let mut buf = [0u8; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                },
+            }
+        }
+
+        String::from_utf8(bytes).map_err(|_| format!("invalid UTF-8 in {}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+    use format::PAKRecord;
+
+    fn sample_pak() -> PAKFile {
+        PAKFile::new(UTF_8, vec![
+            PAKRecord {
+                name: "news".into(),
+                lines: vec![
+                    "Taxes are \"too high\"".into(),
+                    "".into(),
+                    "Two\nlines\n".into(),
+                ],
+            },
+            PAKRecord {
+                name: "news".into(),
+                lines: vec![
+                    "C:\\path\t\x01".into(),
+                ],
+            },
+            PAKRecord {
+                name: "empty".into(),
+                lines: vec![],
+            },
+        ])
+    }
+
+    #[test]
+    fn from_pak() {
+        let po = POFile::from_pak(&sample_pak());
+        let text = po.as_string();
+
+        assert_eq!(po.entries.iter().map(|e| e.msgctxt.clone().unwrap()).collect::<Vec<String>>(),
+            vec!["news:0", "news:2", "news~2:0"]);
+        assert!(text.contains("msgctxt \"news:2\"\nmsgid \"\"\n\"Two\\n\"\n\"lines\\n\"\nmsgstr \"\"\n"));
+        assert!(text.contains("msgid \"C:\\\\path\\t\\001\"\n"));
+        assert_eq!(POFile::parse(&text).unwrap(), po);
+    }
+
+    #[test]
+    fn parse() {
+        let text = "\
+            # Translator comment\n\
+            msgid \"\"\n\
+            msgstr \"\"\n\
+            \"Content-Type: text/plain; charset=UTF-8\\n\"\n\
+            \n\
+            #: somewhere\n\
+            #, c-format, fuzzy\n\
+            msgctxt \"a:0\"\n\
+            msgid \"Hello\"\n\
+            msgstr \"\\x48ola \\303\\251\"\n\
+            msgctxt \"a:1\"\n\
+            msgid \"One\"\n\
+            msgid_plural \"Many\"\n\
+            msgstr[0] \"Uno\"\n\
+            msgstr[1] \"Muchos\"\n\
+            \n\
+            #~ msgid \"Obsolete\"\n\
+            #~ msgstr \"Obsoleto\"\n\
+        ";
+        let po = POFile::parse(text).unwrap();
+
+        assert_eq!(po.header, "Content-Type: text/plain; charset=UTF-8\n");
+        assert_eq!(po.entries, vec![
+            POEntry {
+                msgctxt: Some("a:0".into()),
+                msgid: "Hello".into(),
+                msgstr: "Hola é".into(),
+                fuzzy: true,
+            },
+            POEntry {
+                msgctxt: Some("a:1".into()),
+                msgid: "One".into(),
+                msgstr: "Uno".into(),
+                fuzzy: false,
+            },
+        ]);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let texts = [
+            "msgid \"a\"\n",
+            "msgid \"a\nmsgstr \"\"\n",
+            "\"a\"\n",
+            "msgid \"a\"\nmsgid \"b\"\nmsgstr \"\"\n",
+            "msgid \"\\q\"\nmsgstr \"\"\n",
+            "msgfoo \"a\"\n",
+            "msgid \"a\"\nmsgstr \"\"\n\n\"b\"\n",
+        ];
+
+        for text in texts.iter() {
+            assert!(POFile::parse(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn merge_into() {
+        let mut po = POFile::from_pak(&sample_pak());
+        po.entries[0].msgstr = "Les impôts sont \"trop élevés\"".into();
+        po.entries[1].msgstr = "Deux\nlignes\n".into();
+        po.entries[1].fuzzy = true;
+        po.entries.push(POEntry {
+            msgctxt: Some("gone:0".into()),
+            msgid: "Gone".into(),
+            msgstr: "Parti".into(),
+            fuzzy: false,
+        });
+
+        let mut pak = sample_pak();
+        pak.records[1].lines[0] = "Edited since".into();
+
+        let report = po.merge_into(&mut pak, false);
+
+        assert_eq!(report, POMergeReport {
+            translated: 1,
+            fuzzy: vec!["news:2".into()],
+            untranslated: vec![],
+            changed: vec!["news~2:0".into()],
+            unknown: vec!["gone:0".into()],
+        });
+        assert_eq!(pak.records[0].lines, vec!["Les impôts sont \"trop élevés\"", "", "Two\nlines\n"]);
+
+        let mut pak = sample_pak();
+        let report = po.merge_into(&mut pak, true);
+
+        assert_eq!(report.translated, 2);
+        assert_eq!(report.fuzzy, vec!["news:2"]);
+        assert_eq!(report.untranslated, vec!["news~2:0"]);
+        assert_eq!(pak.records[0].lines[2], "Deux\nlignes\n");
+    }
+}
@@ -0,0 +1,202 @@
+use std::io::{Cursor, Write};
+use error::*;
+use format::{Image, ImageType, IXFFile, RefPackCompression, RefPackMode};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+pub const PREVIEW_HEADER_LENGTH: usize = 12;
+
+/// A savegame preview (thumbnail) record. See `docs/sc3.txt` for the layout.
+#[derive(Debug)]
+pub struct PreviewImage {
+    pub image: Image,
+    /// Whether the record body is wrapped in RefPack compression.
+    pub compressed: bool,
+}
+
+impl PreviewImage {
+
+    pub fn parse(body: &[u8]) -> Result<PreviewImage> {
+        let compressed = RefPackCompression::is_compressed(body);
+        let uncompressed;
+        let data = if compressed {
+            uncompressed = RefPackCompression::uncompress(body)?;
+            &uncompressed
+        } else {
+            body
+        };
+
+        if data.len() < PREVIEW_HEADER_LENGTH {
+            return Err(Error::Image(format!("preview record too short: {} bytes", data.len())));
+        }
+
+        let mut cursor = Cursor::new(data);
+        let image_type = ImageType::from_game_value(cursor.read_u32::<LE>()?)?;
+        let width = cursor.read_u32::<LE>()? as usize;
+        let height = cursor.read_u32::<LE>()? as usize;
+
+        if width == 0 || height == 0 {
+            return Err(Error::Image(format!("empty preview image: ({}, {})", width, height)));
+        }
+
+        Ok(PreviewImage {
+            image: Image::new(image_type, width, height, data[PREVIEW_HEADER_LENGTH..].to_vec())?,
+            compressed,
+        })
+    }
+
+    /// Finds the record that holds the preview image, returning its index. The TGI of preview records is not known,
+    /// so records are recognized by their header and an exact body length; more than one match is an error, since
+    /// picking one would be a guess (see `find_by_tgi`).
+    pub fn find(ixf: &IXFFile) -> Result<(usize, PreviewImage)> {
+        let mut found = ixf.records.iter()
+            .enumerate()
+            .filter_map(|(i, r)| PreviewImage::parse(&r.body).ok().map(|p| (i, p)))
+            .collect::<Vec<(usize, PreviewImage)>>();
+
+        if found.len() > 1 {
+            let tgis = found.iter()
+                .map(|&(i, _)| {
+                    let r = &ixf.records[i];
+                    format!("{:X?}_{:X?}_{:X?}", r.type_id, r.group_id, r.instance_id)
+                })
+                .collect::<Vec<String>>();
+
+            return Err(Error::Image(format!("more than one record looks like a preview image: {}", tgis.join(", "))));
+        }
+
+        found.pop().ok_or_else(|| Error::Image("no preview image record found".into()))
+    }
+
+    /// Parses the preview image in the record with the given type, group and instance IDs, returning its index.
+    pub fn find_by_tgi(ixf: &IXFFile, type_id: u32, group_id: u32, instance_id: u32) -> Result<(usize, PreviewImage)> {
+        let index = ixf.records.iter()
+            .position(|r| (r.type_id, r.group_id, r.instance_id) == (type_id, group_id, instance_id))
+            .ok_or_else(|| Error::Image(format!("no record {:X?}_{:X?}_{:X?}", type_id, group_id, instance_id)))?;
+
+        Ok((index, PreviewImage::parse(&ixf.records[index].body)?))
+    }
+
+    /// Puts `image` in place of the current image and returns the new record body, or `None` if the pixels did not
+    /// change, in which case the record should keep its original bytes rather than be compressed again.
+    pub fn replace_image(&mut self, image: Image) -> Result<Option<Vec<u8>>> {
+        if image.image_type() == self.image.image_type() && image.data() == self.image.data()
+            && (image.width(), image.height()) == (self.image.width(), self.image.height()) {
+            return Ok(None);
+        }
+
+        self.image = image;
+        self.as_vec().map(Some)
+    }
+
+    pub fn as_vec(&self) -> Result<Vec<u8>> {
+        let mut cursor = Cursor::new(Vec::new());
+
+        let image_type = self.image.image_type();
+        let game_value = image_type.as_game_value()
+            .ok_or_else(|| Error::Image(format!("{:?} has no known game value", image_type)))?;
+
+        cursor.write_u32::<LE>(game_value)?;
+        cursor.write_u32::<LE>(self.image.width() as u32)?;
+        cursor.write_u32::<LE>(self.image.height() as u32)?;
+        cursor.write_all(self.image.data())?;
+
+        let data = cursor.into_inner();
+
+        if self.compressed {
+            return RefPackCompression::compress_with_mode(&data, RefPackMode::Greedy);
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use format::IXFRecord;
+
+    const DATA_0: &[u8] = &[
+        0x07, 0x00, 0x00, 0x00,
+        0x02, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00,
+
+        0x00, 0xF8,
+        0x1F, 0x00,
+    ];
+
+    #[test]
+    fn parse() {
+        let preview = PreviewImage::parse(DATA_0).unwrap();
+
+        assert!(!preview.compressed);
+        assert_eq!(preview.image.image_type(), ImageType::R5G6B5);
+        assert_eq!(preview.image.width(), 2);
+        assert_eq!(preview.image.height(), 1);
+        assert_eq!(preview.image.to_rgb8(), vec![0xFF, 0x00, 0x00, 0x00, 0x00, 0xFF]);
+        assert_eq!(preview.as_vec().unwrap(), DATA_0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn parse_size_mismatch() {
+        PreviewImage::parse(&DATA_0[..DATA_0.len() - 1]).unwrap();
+    }
+
+    #[test]
+    fn find() {
+        let compressed = RefPackCompression::compress_with_mode(DATA_0, RefPackMode::Greedy).unwrap();
+        let ixf = IXFFile {
+            records: vec![
+                IXFRecord {
+                    type_id: 1,
+                    group_id: 2,
+                    instance_id: 3,
+                    body: vec![0x07, 0x00, 0x00, 0x00, 0xDE, 0xAD],
+                },
+                IXFRecord {
+                    type_id: 4,
+                    group_id: 5,
+                    instance_id: 6,
+                    body: compressed.clone(),
+                },
+            ],
+        };
+
+        let (index, preview) = PreviewImage::find(&ixf).unwrap();
+
+        assert_eq!(index, 1);
+        assert!(preview.compressed);
+        assert_eq!(preview.image.width(), 2);
+        assert_eq!(preview.as_vec().unwrap(), compressed);
+    }
+
+    #[test]
+    fn replace_image() {
+        let mut preview = PreviewImage::parse(DATA_0).unwrap();
+        let same = Image::new(ImageType::R5G6B5, 2, 1, DATA_0[PREVIEW_HEADER_LENGTH..].to_vec()).unwrap();
+        let changed = Image::new(ImageType::R5G6B5, 2, 1, vec![0x1F, 0x00, 0x00, 0xF8]).unwrap();
+
+        assert!(preview.replace_image(same).unwrap().is_none());
+        assert_eq!(preview.replace_image(changed).unwrap().unwrap()[PREVIEW_HEADER_LENGTH..], [0x1F, 0x00, 0x00, 0xF8]);
+    }
+
+    #[test]
+    fn find_ambiguous() {
+        let mut ixf = IXFFile {
+            records: (0..3).map(|i| IXFRecord {
+                type_id: 1,
+                group_id: 2,
+                instance_id: i,
+                body: if i == 1 { DATA_0[..DATA_0.len() - 2].to_vec() } else { DATA_0.to_vec() },
+            }).collect(),
+        };
+
+        assert!(PreviewImage::find(&ixf).is_err());
+        assert_eq!(PreviewImage::find_by_tgi(&ixf, 1, 2, 2).unwrap().0, 2);
+        assert!(PreviewImage::find_by_tgi(&ixf, 1, 2, 1).is_err());
+        assert!(PreviewImage::find_by_tgi(&ixf, 1, 2, 3).is_err());
+
+        ixf.records.pop();
+        assert_eq!(PreviewImage::find(&ixf).unwrap().0, 0);
+    }
+}
@@ -10,10 +10,15 @@ pub struct RefPackCompression;
  * http://www.wiki.sc4devotion.com/index.php?title=QFS_compression
  * http://wiki.niotso.org/RefPack
  */
-
 impl RefPackCompression {
 
-    pub fn uncompress(data: &[u8]) -> Result<Vec<u8>> {
+    /// Checks whether `data` starts with a RefPack header.
+    pub fn is_compressed(data: &[u8]) -> bool {
+        data.len() >= 5 && data[0] == (REFPACK_COMPRESSION_ID >> 8) as u8 && data[1] == REFPACK_COMPRESSION_ID as u8
+    }
+
+    /// Returns the uncompressed length declared in the header of a RefPack stream.
+    pub fn uncompressed_len(data: &[u8]) -> Result<usize> {
         let mut cursor = Cursor::new(data);
         let ident = cursor.read_u16::<BE>()?;
 
@@ -21,57 +26,81 @@ impl RefPackCompression {
             return Err(Error::RefPackCompression(format!("invalid identifier: 0x{:04X?}", ident)))
         }
 
-        let uncompressed_len = cursor.read_u24::<BE>()? as usize;
+        Ok(cursor.read_u24::<BE>()? as usize)
+    }
+
+    pub fn uncompress(data: &[u8]) -> Result<Vec<u8>> {
+        let mut decoded = vec![0u8; Self::uncompressed_len(data)?];
+        Self::uncompress_into(data, &mut decoded)?;
+        Ok(decoded)
+    }
+
+    /// Decodes `data` into the beginning of `output` without allocating, returning the number of bytes written
+    /// (i.e., the uncompressed length declared in the header). `output` must be at least that long.
+    pub fn uncompress_into(data: &[u8], output: &mut [u8]) -> Result<usize> {
+        let uncompressed_len = Self::uncompressed_len(data)?;
+
+        if output.len() < uncompressed_len {
+            return Err(Error::RefPackCompression(format!("output buffer too small: {} < {}", output.len(),
+                uncompressed_len)));
+        }
 
-        let mut decoded = Vec::new();
+        let output = &mut output[..uncompressed_len];
+        let mut pos = 5;
+        let mut decoded_len = 0;
         let mut stop_command = false;
 
-        while cursor.position() < data.len() as u64 && !stop_command {
-            let b0 = cursor.read_u8()? as usize;
+        while pos < data.len() && !stop_command {
+            let b0 = data[pos] as usize;
             let append_len;
             let copy_offset;
             let copy_len;
 
+            pos += 1;
+
             match b0 {
-                0x00 ... 0x7F => {
-                    let b1 = cursor.read_u8()? as usize;
+                0x00..=0x7F => {
+                    let b1 = Self::read_bytes(data, &mut pos, 1)?[0] as usize;
 
                     append_len = b0 & 0x03;
                     copy_offset = ((b0 & 0x60) << 3) + b1 + 1;
                     copy_len = ((b0 & 0x1C) >> 2) + 3;
                 },
-                0x80 ... 0xBF => {
-                    let b1 = cursor.read_u8()? as usize;
-                    let b2 = cursor.read_u8()? as usize;
+                0x80..=0xBF => {
+                    let b = Self::read_bytes(data, &mut pos, 2)?;
+                    let b1 = b[0] as usize;
+                    let b2 = b[1] as usize;
 
                     append_len = ((b1 & 0xC0) >> 6) & 0x03;
                     copy_offset = ((b1 & 0x3F) << 8) + b2 + 1;
                     copy_len = (b0 & 0x3F) + 4;
                 },
-                0xC0 ... 0xCF => {
-                    let b1 = cursor.read_u8()? as usize;
-                    let b2 = cursor.read_u8()? as usize;
-                    let b3 = cursor.read_u8()? as usize;
+                0xC0..=0xCF => {
+                    let b = Self::read_bytes(data, &mut pos, 3)?;
+                    let b1 = b[0] as usize;
+                    let b2 = b[1] as usize;
+                    let b3 = b[2] as usize;
 
                     append_len = b0 & 0x03;
                     copy_offset = (b1 << 8) + b2 + 1;
                     copy_len = ((b0 & 0x1C) << 6) + b3 + 5;
                 },
-                0xD0 ... 0xDF => {
-                    let b1 = cursor.read_u8()? as usize;
-                    let b2 = cursor.read_u8()? as usize;
-                    let b3 = cursor.read_u8()? as usize;
+                0xD0..=0xDF => {
+                    let b = Self::read_bytes(data, &mut pos, 3)?;
+                    let b1 = b[0] as usize;
+                    let b2 = b[1] as usize;
+                    let b3 = b[2] as usize;
 
                     append_len = b0 & 0x03;
                     copy_offset = ((b0 & 0x10) << 12) + (b1 << 8) + b2 + 1;
                     copy_len = ((b0 & 0x0C) << 6) + b3 + 5;
                 },
-                0xE0 ... 0xFB => {
+                0xE0..=0xFB => {
                     append_len = ((b0 & 0x1F) << 2) + 4;
                     copy_offset = 0;
                     copy_len = 0;
                 },
-                0xFC ... 0xFF => {
+                0xFC..=0xFF => {
                     append_len = b0 & 0x03;
                     copy_offset = 0;
                     copy_len = 0;
@@ -80,66 +109,356 @@ impl RefPackCompression {
                 _ => unreachable!()
             }
 
-            for _ in 0..append_len {
-                decoded.push(cursor.read_u8()?);
+            if decoded_len + append_len + copy_len > uncompressed_len {
+                return Err(Error::RefPackCompression(format!("uncompressed length exceeded: {} > {}",
+                    decoded_len + append_len + copy_len, uncompressed_len)));
             }
 
-            if copy_len <= 0 {
+            let literal = Self::read_bytes(data, &mut pos, append_len)?;
+            output[decoded_len..decoded_len + append_len].copy_from_slice(literal);
+            decoded_len += append_len;
+
+            if copy_len == 0 {
                 continue
             }
 
-            if copy_offset > decoded.len() {
+            if copy_offset > decoded_len {
                 return Err(Error::RefPackCompression(format!("decompression start index out of bounds: len ({}) < {}",
-                    decoded.len(), copy_offset)))
+                    decoded_len, copy_offset)))
             }
 
-            let start = decoded.len() - copy_offset;
-
-            for i in start..start + copy_len {
-                let b = decoded[i];
-                decoded.push(b);
-            }
+            Self::copy_back_reference(output, decoded_len, copy_offset, copy_len);
+            decoded_len += copy_len;
         }
 
-        if decoded.len() != uncompressed_len {
-            return Err(Error::RefPackCompression(format!("uncompressed length mismatched: {} != {}", decoded.len(),
+        if decoded_len != uncompressed_len {
+            return Err(Error::RefPackCompression(format!("uncompressed length mismatched: {} != {}", decoded_len,
                 uncompressed_len)));
         }
 
-        Ok(decoded)
+        Ok(uncompressed_len)
+    }
+
+    fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+        if data.len() - *pos < len {
+            return Err(Error::RefPackCompression(format!("unexpected end of stream at 0x{:X?}", data.len())));
+        }
+
+        let bytes = &data[*pos..*pos + len];
+        *pos += len;
+
+        Ok(bytes)
+    }
+
+    /// Copies `len` bytes starting `offset` bytes behind `dest` to `dest`. If the ranges overlap, the source is a
+    /// repeating pattern with period `offset`, so it is copied in chunks that double in size as the pattern grows.
+    fn copy_back_reference(buffer: &mut [u8], dest: usize, offset: usize, len: usize) {
+        let start = dest - offset;
+
+        if offset >= len {
+            buffer.copy_within(start..start + len, dest);
+            return
+        }
+
+        let mut copied = 0;
+
+        while copied < len {
+            let chunk = (offset + copied).min(len - copied);
+            buffer.copy_within(start..start + chunk, dest + copied);
+            copied += chunk;
+        }
     }
 
     pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
-        let mut cursor = Cursor::new(Vec::new());
+        Self::compress_with_mode(data, RefPackMode::Literal)
+    }
+
+    pub fn compress_with_mode(data: &[u8], mode: RefPackMode) -> Result<Vec<u8>> {
+        if data.len() > REFPACK_MAX_UNCOMPRESSED_LEN {
+            return Err(Error::RefPackCompression(format!("data too large: {} > {}", data.len(),
+                REFPACK_MAX_UNCOMPRESSED_LEN)));
+        }
+
+        let mut cursor = Cursor::new(Vec::with_capacity(data.len() + data.len() / 112 + 6));
 
         cursor.write_u16::<BE>(REFPACK_COMPRESSION_ID)?;
         cursor.write_u24::<BE>(data.len() as u32)?;
 
-        let mut remaining = data.len();
+        let mut finder = match mode {
+            RefPackMode::Literal => None,
+            RefPackMode::Greedy => Some(MatchFinder::new(data.len())),
+        };
+        let mut literal_start = 0;
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let found = match finder {
+                Some(ref mut finder) => {
+                    let found = finder.find(data, pos);
+                    finder.insert(data, pos);
+                    found
+                },
+                None => None,
+            };
+
+            let (copy_offset, copy_len) = match found {
+                Some(found) => found,
+                None => {
+                    pos += 1;
+                    continue
+                },
+            };
+
+            if let Some(ref mut finder) = finder {
+                for skipped in pos + 1..pos + copy_len {
+                    finder.insert(data, skipped);
+                }
+            }
+
+            let literals = Self::write_literals(&mut cursor, &data[literal_start..pos])?;
+            Self::write_copy(&mut cursor, literals, copy_offset, copy_len)?;
 
-        while remaining >= 112 {
-            let off = data.len() - remaining;
-            cursor.write_u8(0xFB)?;
-            cursor.write_all(&data[off..off + 112])?;
-            remaining -= 112;
+            pos += copy_len;
+            literal_start = pos;
         }
 
-        assert!(remaining < 112);
+        let literals = Self::write_literals(&mut cursor, &data[literal_start..])?;
 
-        let left_bits = ((remaining - 4) / 4) as u8;
-        let left = left_bits as usize * 4 + 4;
-        let off = data.len() - remaining;
+        cursor.write_u8(0xFC | literals.len() as u8)?;
+        cursor.write_all(literals)?;
 
-        remaining -= left;
+        Ok(cursor.into_inner())
+    }
 
-        cursor.write_u8(0b11100000u8 | left_bits)?;
-        cursor.write_all(&data[off..off + left])?;
+    /// Writes literal runs in multiples of 4 bytes (at most 112 each), returning the remaining 0-3 bytes that have to
+    /// be carried by the next command.
+    fn write_literals<'a>(cursor: &mut Cursor<Vec<u8>>, literals: &'a [u8]) -> Result<&'a [u8]> {
+        let mut remaining = literals;
 
-        assert!(remaining <= 3);
+        while remaining.len() >= 4 {
+            let len = (remaining.len() & !0x03).min(112);
 
-        cursor.write_u8(0b11111100u8 | remaining as u8)?;
-        cursor.write_all(&data[data.len() - remaining..])?;
+            cursor.write_u8(0xE0 | ((len - 4) >> 2) as u8)?;
+            cursor.write_all(&remaining[..len])?;
 
-        Ok(cursor.into_inner())
+            remaining = &remaining[len..];
+        }
+
+        Ok(remaining)
+    }
+
+    /// Writes a back-reference using the shortest command that can hold it, followed by the 0-3 literal bytes.
+    fn write_copy(cursor: &mut Cursor<Vec<u8>>, literals: &[u8], copy_offset: usize, copy_len: usize) -> Result<()> {
+        let append_len = literals.len();
+        let offset = copy_offset - 1;
+
+        if copy_len <= 10 && copy_offset <= 1024 {
+            cursor.write_u8((((offset >> 3) & 0x60) | ((copy_len - 3) << 2) | append_len) as u8)?;
+            cursor.write_u8(offset as u8)?;
+        } else if copy_len <= 67 && copy_offset <= 16384 {
+            cursor.write_u8((0x80 | (copy_len - 4)) as u8)?;
+            cursor.write_u8(((append_len << 6) | (offset >> 8)) as u8)?;
+            cursor.write_u8(offset as u8)?;
+        } else {
+            let len = copy_len - 5;
+
+            cursor.write_u8((0xC0 | ((offset >> 12) & 0x10) | ((len >> 6) & 0x0C) | append_len) as u8)?;
+            cursor.write_u8((offset >> 8) as u8)?;
+            cursor.write_u8(offset as u8)?;
+            cursor.write_u8(len as u8)?;
+        }
+
+        cursor.write_all(literals)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefPackMode {
+    /// Store everything as literal runs. Fast, but the output is slightly larger than the input.
+    Literal,
+    /// Greedy longest-match encoding: literal runs are flushed in 112-byte blocks, the last 0-3 literals ride on the
+    /// following copy command, the shortest command that fits is always chosen, and the nearest offset wins between
+    /// matches of equal length. Whether the game's own encoder makes the same choices is not verified, so recompressed
+    /// records may differ from the original ones.
+    Greedy,
+}
+
+const REFPACK_MAX_UNCOMPRESSED_LEN: usize = 0xFFFFFF;
+const REFPACK_MAX_COPY_LEN: usize = 1028;
+const REFPACK_MAX_COPY_OFFSET: usize = 0x20000;
+const MATCH_FINDER_HASH_BITS: usize = 16;
+const MATCH_FINDER_MAX_CHAIN: usize = 64;
+const MATCH_FINDER_NONE: u32 = u32::MAX;
+
+/// Hash chains over every 3-byte sequence seen so far, newest first.
+struct MatchFinder {
+    head: Vec<u32>,
+    prev: Vec<u32>,
+}
+
+impl MatchFinder {
+
+    fn new(len: usize) -> MatchFinder {
+        MatchFinder {
+            head: vec![MATCH_FINDER_NONE; 1 << MATCH_FINDER_HASH_BITS],
+            prev: vec![MATCH_FINDER_NONE; len],
+        }
+    }
+
+    fn hash(data: &[u8], pos: usize) -> usize {
+        let key = ((data[pos] as u32) << 16) | ((data[pos + 1] as u32) << 8) | data[pos + 2] as u32;
+        (key.wrapping_mul(2654435761) >> (32 - MATCH_FINDER_HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + 3 > data.len() {
+            return
+        }
+
+        let hash = Self::hash(data, pos);
+
+        self.prev[pos] = self.head[hash];
+        self.head[hash] = pos as u32;
+    }
+
+    /// Returns `(offset, length)` of the longest usable match for the bytes at `pos`.
+    fn find(&self, data: &[u8], pos: usize) -> Option<(usize, usize)> {
+        if pos + 3 > data.len() {
+            return None
+        }
+
+        let max_len = (data.len() - pos).min(REFPACK_MAX_COPY_LEN);
+        let mut candidate = self.head[Self::hash(data, pos)];
+        let mut best = (0, 0);
+
+        for _ in 0..MATCH_FINDER_MAX_CHAIN {
+            if candidate == MATCH_FINDER_NONE || pos - candidate as usize > REFPACK_MAX_COPY_OFFSET {
+                break
+            }
+
+            let start = candidate as usize;
+            let len = data[start..start + max_len].iter()
+                .zip(&data[pos..pos + max_len])
+                .take_while(|&(a, b)| a == b)
+                .count();
+
+            if len > best.1 {
+                best = (pos - start, len);
+
+                if len == max_len {
+                    break
+                }
+            }
+
+            candidate = self.prev[start];
+        }
+
+        match best {
+            (_, len) if len < 3 => None,
+            (offset, 3) if offset > 1024 => None,
+            (offset, 4) if offset > 16384 => None,
+            best => Some(best),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA_0: &[u8] = b"\
+        \x10\xFB\x00\x00\x0C\
+        \xE0abcd\
+        \x14\x03\
+        \xFC\
+    ";
+    const DATA_0_UNCOMPRESSED: &[u8] = b"abcdabcdabcd";
+
+    const DATA_1: &[u8] = b"\
+        \x10\xFB\x00\x00\x16\
+        \xE0xyz!\
+        \x8E\x00\x00\
+        \xFC\
+    ";
+    const DATA_1_UNCOMPRESSED: &[u8] = b"xyz!!!!!!!!!!!!!!!!!!!";
+
+    const DATA_2: &[u8] = b"\
+        \x10\xFB\x00\x00\x0E\
+        \xE00123\
+        \x13\x06456\
+        \xFC\
+    ";
+
+    const DATA_3: &[u8] = b"\
+        \x10\xFB\x00\x00\x64\
+        \xE0ABCD\
+        \xC0\x00\x03\x5B\
+        \xFC\
+    ";
+
+    #[test]
+    fn uncompress() {
+        assert_eq!(RefPackCompression::uncompress(DATA_0).unwrap(), DATA_0_UNCOMPRESSED);
+        assert_eq!(RefPackCompression::uncompress(DATA_1).unwrap(), DATA_1_UNCOMPRESSED);
+    }
+
+    #[test]
+    fn uncompress_into() {
+        let mut buffer = [0xAAu8; 32];
+
+        assert_eq!(RefPackCompression::uncompress_into(DATA_0, &mut buffer).unwrap(), DATA_0_UNCOMPRESSED.len());
+        assert_eq!(&buffer[..DATA_0_UNCOMPRESSED.len()], DATA_0_UNCOMPRESSED);
+        assert!(buffer[DATA_0_UNCOMPRESSED.len()..].iter().all(|&b| b == 0xAA));
+
+        assert_eq!(RefPackCompression::uncompress_into(DATA_1, &mut buffer).unwrap(), DATA_1_UNCOMPRESSED.len());
+        assert_eq!(&buffer[..DATA_1_UNCOMPRESSED.len()], DATA_1_UNCOMPRESSED);
+    }
+
+    #[test]
+    #[should_panic]
+    fn uncompress_into_small_buffer() {
+        let mut buffer = [0u8; 8];
+        RefPackCompression::uncompress_into(DATA_0, &mut buffer).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn uncompress_truncated() {
+        RefPackCompression::uncompress(&DATA_0[..DATA_0.len() - 3]).unwrap();
+    }
+
+    #[test]
+    fn reencode() {
+        for &len in &[0, 2, 200, 224] {
+            let data = (0..len as u32).map(|x| (x * 7) as u8).collect::<Vec<u8>>();
+            let compressed = RefPackCompression::compress(&data).unwrap();
+
+            assert_eq!(RefPackCompression::uncompress(&compressed).unwrap(), data);
+        }
+    }
+
+    /// The streams are hand-written in the layout described at `RefPackMode::Greedy`, not taken from the game, so
+    /// this only checks that the encoder follows its own rules.
+    #[test]
+    fn greedy_layout() {
+        for &stream in &[DATA_0, DATA_1, DATA_2, DATA_3] {
+            let data = RefPackCompression::uncompress(stream).unwrap();
+            let compressed = RefPackCompression::compress_with_mode(&data, RefPackMode::Greedy).unwrap();
+
+            assert_eq!(compressed, stream);
+        }
+    }
+
+    #[test]
+    fn compress_greedy() {
+        let data = (0..0x30000u32)
+            .map(|x| if x % 5000 < 3000 { (x % 37) as u8 } else { (x.wrapping_mul(x) >> 7) as u8 })
+            .collect::<Vec<u8>>();
+        let compressed = RefPackCompression::compress_with_mode(&data, RefPackMode::Greedy).unwrap();
+
+        assert!(compressed.len() < data.len() / 2);
+        assert_eq!(RefPackCompression::uncompress(&compressed).unwrap(), data);
     }
 }
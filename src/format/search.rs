@@ -0,0 +1,238 @@
+use error::*;
+use format::{IXFFile, PAKFile, RefPackCompression};
+use regex::bytes::{Regex, RegexBuilder};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchPatternKind {
+    /// Literal text.
+    Text,
+    /// A regular expression (see the `regex` crate for the syntax).
+    Regex,
+    /// Hexadecimal bytes, optionally separated by spaces, where `??` matches any byte.
+    Hex,
+}
+
+/// Where a match was found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchLocation {
+    /// The file itself, which is neither a PAK nor an IXF file.
+    File,
+    /// A line of a PAK record, decoded to UTF-8. The record is named as by `PAKFile::unique_names`.
+    PAKLine {
+        record: String,
+        line: usize,
+    },
+    /// The body of an IXF record, after uncompressing it if it was compressed.
+    IXFRecord {
+        type_id: u32,
+        group_id: u32,
+        instance_id: u32,
+        compressed: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub location: SearchLocation,
+    /// Byte offset of the match in the file, the UTF-8 line or the uncompressed record body.
+    pub offset: usize,
+    pub matched: Vec<u8>,
+}
+
+pub struct Searcher {
+    regex: Regex,
+}
+
+impl Searcher {
+
+    /// Makes a searcher for `pattern`. `ignore_case` has no effect on hexadecimal patterns, which match bytes exactly.
+    pub fn new(pattern: &str, kind: SearchPatternKind, ignore_case: bool) -> Result<Searcher> {
+        let pattern = match kind {
+            SearchPatternKind::Text => ::regex::escape(pattern),
+            SearchPatternKind::Regex => pattern.into(),
+            SearchPatternKind::Hex => Self::hex_pattern(pattern)?,
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case && kind != SearchPatternKind::Hex)
+            .build()
+            .map_err(|x| Error::OtherError(Box::new(x)))?;
+
+        Ok(Searcher {
+            regex,
+        })
+    }
+
+    fn hex_pattern(hex: &str) -> Result<String> {
+        let digits = hex.chars().filter(|c| !c.is_whitespace()).collect::<Vec<char>>();
+
+        if digits.is_empty() || digits.len() % 2 != 0 {
+            return Err(Error::from(format!("invalid hexadecimal pattern: {}", hex)));
+        }
+
+        let mut pattern = String::from("(?s-u)");
+
+        for pair in digits.chunks(2) {
+            match pair {
+                ['?', '?'] => pattern.push('.'),
+                [a, b] if a.is_ascii_hexdigit() && b.is_ascii_hexdigit() => {
+                    pattern.push_str("\\x");
+                    pattern.push(*a);
+                    pattern.push(*b);
+                },
+                _ => return Err(Error::from(format!("invalid hexadecimal pattern: {}", hex))),
+            }
+        }
+
+        Ok(pattern)
+    }
+
+    /// Searches a file, looking inside it if it is an IXF file (recognized by its header) or if `is_pak` is set.
+    pub fn search_file(&self, data: &[u8], is_pak: bool) -> Result<Vec<SearchHit>> {
+        if data.starts_with(::format::IXF_FILE_HEADER_IDENTIFIER) {
+            return Ok(self.search_ixf(&IXFFile::parse(data, true)?));
+        }
+
+        if is_pak {
+            return Ok(self.search_pak(&PAKFile::parse(data)?));
+        }
+
+        Ok(self.find(data, &SearchLocation::File))
+    }
+
+    pub fn search_pak(&self, pak: &PAKFile) -> Vec<SearchHit> {
+        let mut hits = Vec::new();
+
+        for (record, name) in pak.records.iter().zip(pak.unique_names()) {
+            for (i, line) in record.lines.iter().enumerate() {
+                hits.extend(self.find(line.as_bytes(), &SearchLocation::PAKLine {
+                    record: name.clone(),
+                    line: i,
+                }));
+            }
+        }
+
+        hits
+    }
+
+    /// Searches every record body. Compressed bodies are uncompressed first, or searched as they are if they do not
+    /// uncompress.
+    pub fn search_ixf(&self, ixf: &IXFFile) -> Vec<SearchHit> {
+        let mut hits = Vec::new();
+
+        for record in ixf.records.iter() {
+            let uncompressed = if RefPackCompression::is_compressed(&record.body) {
+                RefPackCompression::uncompress(&record.body).ok()
+            } else {
+                None
+            };
+
+            hits.extend(self.find(uncompressed.as_ref().unwrap_or(&record.body), &SearchLocation::IXFRecord {
+                type_id: record.type_id,
+                group_id: record.group_id,
+                instance_id: record.instance_id,
+                compressed: uncompressed.is_some(),
+            }));
+        }
+
+        hits
+    }
+
+    fn find(&self, haystack: &[u8], location: &SearchLocation) -> Vec<SearchHit> {
+        self.regex.find_iter(haystack)
+            .map(|m| SearchHit {
+                location: location.clone(),
+                offset: m.start(),
+                matched: m.as_bytes().to_vec(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::WINDOWS_1252;
+    use format::{IXFRecord, PAKRecord, RefPackMode};
+
+    #[test]
+    fn patterns() {
+        let data = b"Caf\xC3\xA9 CAFE cafe.\x00\xDE\xAD\xBE\xEF";
+        let offsets = |searcher: Searcher| searcher.find(data, &SearchLocation::File).iter()
+            .map(|h| h.offset)
+            .collect::<Vec<usize>>();
+
+        assert_eq!(offsets(Searcher::new("cafe.", SearchPatternKind::Text, false).unwrap()), vec![11]);
+        assert_eq!(offsets(Searcher::new("CAFÉ", SearchPatternKind::Text, true).unwrap()), vec![0]);
+        assert_eq!(offsets(Searcher::new(r"caf\w", SearchPatternKind::Regex, true).unwrap()), vec![0, 6, 11]);
+        assert_eq!(offsets(Searcher::new("de ad ?? EF", SearchPatternKind::Hex, false).unwrap()), vec![17]);
+        assert_eq!(offsets(Searcher::new("43", SearchPatternKind::Hex, true).unwrap()), vec![0, 6]);
+        assert!(Searcher::new("DEA", SearchPatternKind::Hex, false).is_err());
+        assert!(Searcher::new("DE AG", SearchPatternKind::Hex, false).is_err());
+        assert!(Searcher::new("(", SearchPatternKind::Regex, false).is_err());
+    }
+
+    #[test]
+    fn search_file() {
+        let searcher = Searcher::new("mayor", SearchPatternKind::Text, true).unwrap();
+        let pak = PAKFile::new(WINDOWS_1252, vec![
+            PAKRecord {
+                name: "news".into(),
+                lines: vec!["The Mayor".into(), "Café mayor, MAYOR".into()],
+            },
+            PAKRecord {
+                name: "news".into(),
+                lines: vec!["mayor".into()],
+            },
+        ]);
+        let hits = searcher.search_file(&pak.as_vec().unwrap(), true).unwrap();
+
+        assert_eq!(hits.iter().map(|h| (h.location.clone(), h.offset)).collect::<Vec<_>>(), vec![
+            (SearchLocation::PAKLine { record: "news".into(), line: 0 }, 4),
+            (SearchLocation::PAKLine { record: "news".into(), line: 1 }, 6),
+            (SearchLocation::PAKLine { record: "news".into(), line: 1 }, 13),
+            (SearchLocation::PAKLine { record: "news~2".into(), line: 0 }, 0),
+        ]);
+
+        let body = b"..the mayor is the mayor is the mayor..".to_vec();
+        let ixf = IXFFile {
+            records: vec![
+                IXFRecord {
+                    type_id: 1,
+                    group_id: 2,
+                    instance_id: 3,
+                    body: RefPackCompression::compress_with_mode(&body, RefPackMode::Greedy).unwrap(),
+                },
+                IXFRecord {
+                    type_id: 4,
+                    group_id: 5,
+                    instance_id: 6,
+                    body: body.clone(),
+                },
+            ],
+        };
+        let hits = searcher.search_file(&ixf.as_vec().unwrap(), false).unwrap();
+
+        assert_eq!(hits.len(), 6);
+        assert_eq!(hits[2], SearchHit {
+            location: SearchLocation::IXFRecord {
+                type_id: 1,
+                group_id: 2,
+                instance_id: 3,
+                compressed: true,
+            },
+            offset: 32,
+            matched: b"mayor".to_vec(),
+        });
+        assert_eq!(hits[3].location, SearchLocation::IXFRecord {
+            type_id: 4,
+            group_id: 5,
+            instance_id: 6,
+            compressed: false,
+        });
+
+        let hits = searcher.search_file(&body, false).unwrap();
+
+        assert_eq!(hits.iter().map(|h| h.offset).collect::<Vec<usize>>(), vec![6, 19, 32]);
+        assert_eq!(hits[0].location, SearchLocation::File);
+    }
+}
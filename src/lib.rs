@@ -0,0 +1,17 @@
+extern crate byteorder;
+extern crate chardetng;
+extern crate csv;
+extern crate encoding_rs;
+extern crate image;
+extern crate rayon;
+extern crate regex;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
+#[cfg(test)]
+#[macro_use]
+extern crate lazy_static;
+
+pub mod error;
+pub mod format;
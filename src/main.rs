@@ -1,18 +1,17 @@
-extern crate byteorder;
 extern crate clap;
+extern crate encoding_rs;
 extern crate image;
-#[macro_use]
-extern crate lazy_static;
+extern crate toolsc3k;
 
-pub mod error;
-pub mod format;
-
-use error::*;
+use toolsc3k::error::*;
+use toolsc3k::format;
 use std::fs::{self, File};
 use std::path::Path;
 use std::fmt::Write as WF;
-use std::io::{Write as WI, BufReader};
+use std::io::Write as WI;
 use clap::{App, Arg, SubCommand, ArgMatches, AppSettings};
+use encoding_rs::Encoding;
+use image::GenericImageView;
 
 fn main() -> Result<()> {
     let matches = App::new("toolsc3k")
@@ -37,8 +36,97 @@ fn main() -> Result<()> {
                     .required(true)
                 )
             )
+            .subcommand(SubCommand::with_name("compress")
+                .about("Compress uncompressed records with RefPack compression")
+                .arg(Arg::with_name("mode")
+                    .help("Compression mode (\"greedy\" searches for repeated data)")
+                    .long("mode")
+                    .short("m")
+                    .takes_value(true)
+                    .possible_values(&["literal", "greedy"])
+                    .default_value("greedy")
+                )
+                .arg(Arg::with_name("only")
+                    .help("Only compress the records listed in this file, as written by \"uncompress\" (defaults to \
+                        INPUT.compressed; without either, --all is required)")
+                    .long("only")
+                    .short("o")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("all")
+                    .help("Compress every record, even if INPUT.compressed exists")
+                    .long("all")
+                    .short("a")
+                    .conflicts_with("only")
+                )
+                .arg(Arg::with_name("jobs")
+                    .help("Number of threads to use (0 for one per CPU)")
+                    .long("jobs")
+                    .short("j")
+                    .takes_value(true)
+                    .default_value("0")
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The input file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("OUTPUT")
+                    .help("The output file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("uncompress")
+                .about("Uncompress all records with RefPack compression, listing the records that were compressed in \
+                    OUTPUT.compressed")
+                .arg(Arg::with_name("jobs")
+                    .help("Number of threads to use (0 for one per CPU)")
+                    .long("jobs")
+                    .short("j")
+                    .takes_value(true)
+                    .default_value("0")
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The input file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("OUTPUT")
+                    .help("The output file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
             .subcommand(SubCommand::with_name("reconstruct")
                 .about("Reconstruct IXF file from \"dump\" command")
+                .arg(Arg::with_name("compress")
+                    .help("Compress every record with RefPack compression")
+                    .long("compress")
+                    .short("c")
+                )
+                .arg(Arg::with_name("only")
+                    .help("Only compress the records listed in this file, as written by \"uncompress\"")
+                    .long("only")
+                    .short("o")
+                    .takes_value(true)
+                    .conflicts_with("compress")
+                )
+                .arg(Arg::with_name("mode")
+                    .help("Compression mode (\"greedy\" searches for repeated data)")
+                    .long("mode")
+                    .short("m")
+                    .takes_value(true)
+                    .possible_values(&["literal", "greedy"])
+                    .default_value("greedy")
+                )
+                .arg(Arg::with_name("jobs")
+                    .help("Number of threads to use (0 for one per CPU)")
+                    .long("jobs")
+                    .short("j")
+                    .takes_value(true)
+                    .default_value("0")
+                )
                 .arg(Arg::with_name("INPUT")
                     .help("The input directory")
                     .takes_value(true)
@@ -69,6 +157,14 @@ fn main() -> Result<()> {
             )
             .subcommand(SubCommand::with_name("compress")
                 .about("Compress a file with RefPack compression")
+                .arg(Arg::with_name("mode")
+                    .help("Compression mode (\"greedy\" searches for repeated data)")
+                    .long("mode")
+                    .short("m")
+                    .takes_value(true)
+                    .possible_values(&["literal", "greedy"])
+                    .default_value("literal")
+                )
                 .arg(Arg::with_name("INPUT")
                     .help("The input file")
                     .takes_value(true)
@@ -85,13 +181,34 @@ fn main() -> Result<()> {
         .subcommand(SubCommand::with_name("image")
             .about("Command for dealing with game image, such as savefile preview, etc.")
             .arg(Arg::with_name("type")
-                .help("Image format type")
+                .help("Image format type, by game value (5 or 7) or by name (rgb888, argb8888, g1r5g5b5, argb1555, \
+                    r5g6b5 or argb4444)")
                 .long("type")
                 .short("t")
                 .takes_value(true)
             )
-            .subcommand(SubCommand::with_name("to-png")
-                .about("Convert game image to PNG format")
+            .arg(Arg::with_name("color-key")
+                .help("Colour in hexadecimal RRGGBB treated as transparent for formats without alpha (e.g., FF00FF)")
+                .long("color-key")
+                .short("k")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("stride")
+                .help("Bytes from the start of a row to the next one in raw game images (default: no row padding)")
+                .long("stride")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("bottom-up")
+                .help("Store the rows of raw game images from bottom to top")
+                .long("bottom-up")
+            )
+            .arg(Arg::with_name("big-endian")
+                .help("Store the pixels of raw game images in big-endian byte order")
+                .long("big-endian")
+            )
+            .subcommand(SubCommand::with_name("export")
+                .about("Convert game image to PNG, BMP, TGA or GIF format, chosen by the output file extension")
+                .alias("to-png")
                 .arg(Arg::with_name("WIDTH")
                     .help("The image's width")
                     .takes_value(true)
@@ -113,8 +230,164 @@ fn main() -> Result<()> {
                     .required(true)
                 )
             )
-            .subcommand(SubCommand::with_name("from-png")
-                .about("Convert PNG image to game image format")
+            .subcommand(SubCommand::with_name("guess")
+                .about("Guess the width and format of a raw 16-bit game image")
+                .arg(Arg::with_name("min-width")
+                    .help("The smallest width to try")
+                    .long("min-width")
+                    .takes_value(true)
+                    .default_value("8")
+                )
+                .arg(Arg::with_name("max-width")
+                    .help("The largest width to try")
+                    .long("max-width")
+                    .takes_value(true)
+                    .default_value("1024")
+                )
+                .arg(Arg::with_name("count")
+                    .help("Number of candidates to show")
+                    .long("count")
+                    .short("n")
+                    .takes_value(true)
+                    .default_value("10")
+                )
+                .arg(Arg::with_name("sheet")
+                    .help("Write the candidates side by side into this image file, in the printed order")
+                    .long("sheet")
+                    .short("s")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The input file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("get-preview")
+                .about("Extract the preview image of a savegame, detecting its format and size")
+                .arg(Arg::with_name("record")
+                    .help("TYPE_GROUP_INSTANCE of the preview record in hexadecimal, if it is not found by its layout")
+                    .long("record")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The savegame file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("OUTPUT")
+                    .help("The output file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("set-preview")
+                .about("Replace the preview image of a savegame with a PNG, BMP, TGA or GIF image")
+                .arg(Arg::with_name("record")
+                    .help("TYPE_GROUP_INSTANCE of the preview record in hexadecimal, if it is not found by its layout")
+                    .long("record")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("resize")
+                    .help("Resize the new image to the size of the current preview instead of rejecting it")
+                    .long("resize")
+                    .short("r")
+                )
+                .arg(Arg::with_name("dither")
+                    .help("How to reduce colours to the game format")
+                    .long("dither")
+                    .short("d")
+                    .takes_value(true)
+                    .possible_values(&["nearest", "ordered", "floyd-steinberg"])
+                    .default_value("nearest")
+                )
+                .arg(Arg::with_name("no-serpentine")
+                    .help("Scan every row left to right with floyd-steinberg dithering (rows alternate by default)")
+                    .long("no-serpentine")
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The savegame file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("IMAGE")
+                    .help("The new preview image")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("OUTPUT")
+                    .help("The output savegame file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("atlas-export")
+                .about("Pack the image records of an IXF file into one atlas image with a JSON index next to it")
+                .arg(Arg::with_name("record-type")
+                    .help("Type ID in hexadecimal of records that hold images (repeat for several types)")
+                    .long("record-type")
+                    .short("r")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .required(true)
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The IXF file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("ATLAS")
+                    .help("The atlas image; the index is written with the extension .json")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("atlas-import")
+                .about("Slice an edited atlas image back into the records it was exported from")
+                .arg(Arg::with_name("dither")
+                    .help("How to reduce colours to the game format")
+                    .long("dither")
+                    .short("d")
+                    .takes_value(true)
+                    .possible_values(&["nearest", "ordered", "floyd-steinberg"])
+                    .default_value("nearest")
+                )
+                .arg(Arg::with_name("no-serpentine")
+                    .help("Scan every row left to right with floyd-steinberg dithering (rows alternate by default)")
+                    .long("no-serpentine")
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The IXF file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("ATLAS")
+                    .help("The atlas image; the index is read from the same path with the extension .json")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("OUTPUT")
+                    .help("The output IXF file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("import")
+                .about("Convert PNG, BMP, TGA or GIF image to game image format")
+                .alias("from-png")
+                .arg(Arg::with_name("dither")
+                    .help("How to reduce colours to the game format")
+                    .long("dither")
+                    .short("d")
+                    .takes_value(true)
+                    .possible_values(&["nearest", "ordered", "floyd-steinberg"])
+                    .default_value("nearest")
+                )
+                .arg(Arg::with_name("no-serpentine")
+                    .help("Scan every row left to right with floyd-steinberg dithering (rows alternate by default)")
+                    .long("no-serpentine")
+                )
                 .arg(Arg::with_name("INPUT")
                     .help("The input file")
                     .takes_value(true)
@@ -130,6 +403,15 @@ fn main() -> Result<()> {
         )
         .subcommand(SubCommand::with_name("pak")
             .about("Command for dealing with PAK files")
+            .arg(Arg::with_name("encoding")
+                .help("Code page of the strings in the PAK file, such as windows-1252, cp874, cp1250 or shift_jis \
+                    (auto detects it when extracting and uses the extracted one when reconstructing; non-ASCII text \
+                    is only written as UTF-8 when asked for)")
+                .long("encoding")
+                .short("e")
+                .takes_value(true)
+                .default_value("auto")
+            )
             .subcommand(SubCommand::with_name("extract")
                 .about("Extract a PAK file into directory")
                 .arg(Arg::with_name("INPUT")
@@ -145,6 +427,12 @@ fn main() -> Result<()> {
             )
             .subcommand(SubCommand::with_name("reconstruct")
                 .about("Reconstruct a PAK file from directory")
+                .arg(Arg::with_name("deduplicate")
+                    .help("Store identical line blocks once and let the records share them \
+                        (by default, blocks shared in the original file stay shared)")
+                    .long("deduplicate")
+                    .short("d")
+                )
                 .arg(Arg::with_name("INPUT")
                     .help("The input path")
                     .takes_value(true)
@@ -156,8 +444,171 @@ fn main() -> Result<()> {
                     .required(true)
                 )
             )
+            .subcommand(SubCommand::with_name("export")
+                .about("Export a PAK file as JSON, CSV (record, name, line, text, encoding, block) or TOML")
+                .arg(Arg::with_name("format")
+                    .help("The output format (defaults to the extension of OUTPUT)")
+                    .long("format")
+                    .short("f")
+                    .takes_value(true)
+                    .possible_values(&["json", "csv", "toml"])
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The PAK file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("OUTPUT")
+                    .help("The output file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("import")
+                .about("Build a PAK file from JSON, CSV or TOML made by export")
+                .arg(Arg::with_name("deduplicate")
+                    .help("Store identical line blocks once and let the records share them \
+                        (by default, blocks shared in the original file stay shared)")
+                    .long("deduplicate")
+                    .short("d")
+                )
+                .arg(Arg::with_name("format")
+                    .help("The input format (defaults to the extension of INPUT)")
+                    .long("format")
+                    .short("f")
+                    .takes_value(true)
+                    .possible_values(&["json", "csv", "toml"])
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The input file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("OUTPUT")
+                    .help("The PAK file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("compare")
+                .about("Report missing records, line count differences, untranslated lines and mismatched \
+                    placeholders between a PAK file and a translation of it")
+                .arg(Arg::with_name("base-encoding")
+                    .help("Code page of BASE (defaults to --encoding, or detects it)")
+                    .long("base-encoding")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("translated-encoding")
+                    .help("Code page of TRANSLATED (defaults to --encoding, or detects it)")
+                    .long("translated-encoding")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("BASE")
+                    .help("The original PAK file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("TRANSLATED")
+                    .help("The translated PAK file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("get")
+                .about("Print the lines of records by name, escaped as in extracted files, without decoding the rest \
+                    of the PAK file")
+                .arg(Arg::with_name("INPUT")
+                    .help("The PAK file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("NAME")
+                    .help("Names of the records")
+                    .takes_value(true)
+                    .multiple(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("to-po")
+                .about("Export the lines of a PAK file as a gettext PO catalog for translation")
+                .arg(Arg::with_name("INPUT")
+                    .help("The PAK file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("OUTPUT")
+                    .help("The PO file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("from-po")
+                .about("Merge the translations of a PO catalog into a PAK file")
+                .arg(Arg::with_name("deduplicate")
+                    .help("Store identical line blocks once and let the records share them \
+                        (by default, blocks shared in the original file stay shared)")
+                    .long("deduplicate")
+                    .short("d")
+                )
+                .arg(Arg::with_name("fuzzy")
+                    .help("Also merge translations marked fuzzy")
+                    .long("fuzzy")
+                )
+                .arg(Arg::with_name("output-encoding")
+                    .help("Code page of the translated PAK file (defaults to the one of the input)")
+                    .long("output-encoding")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("INPUT")
+                    .help("The PAK file the catalog was exported from")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("PO")
+                    .help("The translated PO file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("OUTPUT")
+                    .help("The translated PAK file")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
             .setting(AppSettings::SubcommandRequired)
         )
+        .subcommand(SubCommand::with_name("search")
+            .about("Search the lines of PAK files, the record bodies of IXF files (uncompressed) and other files for \
+                text or bytes")
+            .arg(Arg::with_name("regex")
+                .help("Read PATTERN as a regular expression")
+                .long("regex")
+                .short("r")
+            )
+            .arg(Arg::with_name("hex")
+                .help("Read PATTERN as hexadecimal bytes, where ?? matches any byte")
+                .long("hex")
+                .short("x")
+                .conflicts_with("regex")
+                .conflicts_with("ignore-case")
+            )
+            .arg(Arg::with_name("ignore-case")
+                .help("Match case-insensitively")
+                .long("ignore-case")
+                .short("i")
+            )
+            .arg(Arg::with_name("PATTERN")
+                .help("The text to search for")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("PATH")
+                .help("Files or directories to search recursively (PAK files are recognized by the .pak extension)")
+                .takes_value(true)
+                .multiple(true)
+                .required(true)
+            )
+        )
         .arg(Arg::with_name("start-offset")
             .help("Set offset of the first byte in hexadecimal to read in the input file")
             .long("start-offset")
@@ -175,6 +626,7 @@ fn main() -> Result<()> {
         ("refpack", Some(sub)) => refpack(sub, start_offset)?,
         ("image", Some(sub)) => image(sub, start_offset)?,
         ("pak", Some(sub)) => pak(sub)?,
+        ("search", Some(sub)) => search(sub)?,
         _ => println!("Unknown subcommand")
     }
 
@@ -188,9 +640,26 @@ fn ixf(matches: &ArgMatches) -> Result<()> {
             sub_m.is_present("skip-bad"),
             sub_m.value_of("to-file")
         )?,
+        ("compress", Some(sub)) => ixf_compress(
+            sub.value_of("INPUT").unwrap(),
+            sub.value_of("OUTPUT").unwrap(),
+            refpack_mode(sub.value_of("mode").unwrap()),
+            sub.value_of("jobs").unwrap().parse::<usize>().map_err(|x| Error::OtherError(Box::new(x)))?,
+            sub.value_of("only"),
+            sub.is_present("all")
+        )?,
+        ("uncompress", Some(sub)) => ixf_uncompress(
+            sub.value_of("INPUT").unwrap(),
+            sub.value_of("OUTPUT").unwrap(),
+            sub.value_of("jobs").unwrap().parse::<usize>().map_err(|x| Error::OtherError(Box::new(x)))?
+        )?,
         ("reconstruct", Some(sub)) => ixf_reconstruct(
             sub.value_of("INPUT").unwrap(),
-            sub.value_of("OUTPUT").unwrap()
+            sub.value_of("OUTPUT").unwrap(),
+            sub.is_present("compress"),
+            sub.value_of("only"),
+            refpack_mode(sub.value_of("mode").unwrap()),
+            sub.value_of("jobs").unwrap().parse::<usize>().map_err(|x| Error::OtherError(Box::new(x)))?
         )?,
         _ => println!("Unknown subcommand")
     }
@@ -213,10 +682,10 @@ fn ixf_dump(filename: &str, skip_bad: bool, binary_dump: Option<&str>) -> Result
         }
 
         let mut out = String::new();
-        writeln!(out, "Record number: {}", i);
-        writeln!(out, "Type ID: 0x{:X?}", r.type_id);
-        writeln!(out, "Group ID: 0x{:X?}", r.group_id);
-        writeln!(out, "Instance ID: 0x{:X?}", r.instance_id);
+        writeln!(out, "Record number: {}", i).unwrap();
+        writeln!(out, "Type ID: 0x{:X?}", r.type_id).unwrap();
+        writeln!(out, "Group ID: 0x{:X?}", r.group_id).unwrap();
+        writeln!(out, "Instance ID: 0x{:X?}", r.instance_id).unwrap();
         
         println!("{}Body:\n{}\n", out, dump_hex(&r.body));
     }
@@ -224,46 +693,129 @@ fn ixf_dump(filename: &str, skip_bad: bool, binary_dump: Option<&str>) -> Result
     Ok(())
 }
 
-fn ixf_reconstruct(input: &str, output: &str) -> Result<()> {
+fn ixf_reconstruct(input: &str, output: &str, compress: bool, only: Option<&str>, mode: format::RefPackMode,
+    jobs: usize) -> Result<()> {
     use std::ffi::OsStr;
 
     let mut ixf = format::IXFFile {
         records: Vec::new()
     };
+    let mut paths = fs::read_dir(input)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
 
-    for entry in fs::read_dir(input)? {
-        let entry = entry?;
-        let path = entry.path();
+    // The directory order depends on the file system, so keep the output stable.
+    paths.sort();
 
+    for path in paths {
         if path.extension() != Some(OsStr::new("bin")) {
             continue;
         }
 
-        let info = path.file_stem()
+        let stem = path.file_stem()
             .ok_or(Error::IXFFile("reconstruct: wrong file name format (file_stem)".into()))?
             .to_str()
-            .ok_or(Error::from("cannot convert &OsStr to &Str"))?
-            .split('_')
-            .collect::<Vec<&str>>();
+            .ok_or(Error::from("cannot convert &OsStr to &Str"))?;
 
-        if info.len() != 3 {
-            println!("Wrong file name format for \"{:?}\", Skipped", path.file_name());
-            continue;
-        }
+        let (type_id, group_id, instance_id) = match parse_tgi(stem) {
+            Ok(tgi) => tgi,
+            Err(_) => {
+                println!("Wrong file name format for \"{:?}\", Skipped", path.file_name());
+                continue;
+            },
+        };
 
         ixf.records.push(format::IXFRecord {
-            type_id: u32::from_str_radix(info[0], 16).map_err(|x| Error::OtherError(Box::new(x)))?,
-            group_id: u32::from_str_radix(info[1], 16).map_err(|x| Error::OtherError(Box::new(x)))?,
-            instance_id: u32::from_str_radix(info[2], 16).map_err(|x| Error::OtherError(Box::new(x)))?,
+            type_id,
+            group_id,
+            instance_id,
             body: fs::read(&path)?,
         })
     }
 
+    if let Some(only) = only {
+        ixf.compress_selected(mode, jobs, &read_compressed_list(only, &ixf)?)?;
+    } else if compress {
+        ixf.compress_records(mode, jobs)?;
+    }
+
+    fs::write(output, ixf.as_vec()?)?;
+
+    Ok(())
+}
+
+fn ixf_compress(input: &str, output: &str, mode: format::RefPackMode, jobs: usize, only: Option<&str>, all: bool)
+    -> Result<()> {
+    let mut ixf = format::IXFFile::parse(&fs::read(input)?, false)?;
+    let default_list = compressed_list_path(input);
+    let only = match only {
+        Some(only) => Some(only.to_string()),
+        None if !all && Path::new(&default_list).exists() => {
+            println!("Compressing the records listed in {}", default_list);
+            Some(default_list)
+        },
+        None if all => None,
+        None => {
+            return Err(Error::from(format!("{} does not exist; pass --only with a list of records, or --all to \
+                compress every record", default_list)));
+        },
+    };
+
+    match only {
+        Some(only) => ixf.compress_selected(mode, jobs, &read_compressed_list(&only, &ixf)?)?,
+        None => ixf.compress_records(mode, jobs)?,
+    }
+
     fs::write(output, ixf.as_vec()?)?;
+    Ok(())
+}
 
+fn ixf_uncompress(input: &str, output: &str, jobs: usize) -> Result<()> {
+    let mut ixf = format::IXFFile::parse(&fs::read(input)?, false)?;
+    let compressed = ixf.uncompress_records(jobs)?;
+    let mut list = String::new();
+
+    for (record, _) in ixf.records.iter().zip(compressed.iter()).filter(|&(_, &c)| c) {
+        writeln!(list, "{:X?}_{:X?}_{:X?}", record.type_id, record.group_id, record.instance_id).unwrap();
+    }
+
+    fs::write(output, ixf.as_vec()?)?;
+    fs::write(compressed_list_path(output), list)?;
+    println!("Uncompressed {} of {} records", compressed.iter().filter(|&&c| c).count(), compressed.len());
     Ok(())
 }
 
+/// Lists the records that `ixf uncompress` uncompressed, one `TYPE_GROUP_INSTANCE` (as in dumped file names) per line,
+/// so that `ixf compress` can restore them.
+fn compressed_list_path(ixf: &str) -> String {
+    format!("{}.compressed", ixf)
+}
+
+/// Which records of `ixf` are listed in the file at `path`.
+fn read_compressed_list(path: &str, ixf: &format::IXFFile) -> Result<Vec<bool>> {
+    let listed = fs::read_to_string(path)?
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| parse_tgi(l.trim()))
+        .collect::<Result<std::collections::HashSet<(u32, u32, u32)>>>()?;
+
+    Ok(ixf.records.iter()
+        .map(|r| listed.contains(&(r.type_id, r.group_id, r.instance_id)))
+        .collect())
+}
+
+/// Parses a `TYPE_GROUP_INSTANCE` name with hexadecimal IDs.
+fn parse_tgi(s: &str) -> Result<(u32, u32, u32)> {
+    let ids = s.split('_')
+        .map(|x| u32::from_str_radix(x, 16).map_err(|x| Error::OtherError(Box::new(x))))
+        .collect::<Result<Vec<u32>>>()?;
+
+    match ids[..] {
+        [type_id, group_id, instance_id] => Ok((type_id, group_id, instance_id)),
+        _ => Err(Error::IXFFile(format!("not a TYPE_GROUP_INSTANCE name: {}", s))),
+    }
+}
+
 fn refpack(matches: &ArgMatches, start_offset: usize) -> Result<()> {
     match matches.subcommand() {
         ("uncompress", Some(sub_m)) => refpack_uncompress(
@@ -273,7 +825,8 @@ fn refpack(matches: &ArgMatches, start_offset: usize) -> Result<()> {
         )?,
         ("compress", Some(sub_m)) => refpack_compress(
             sub_m.value_of("INPUT").unwrap(),
-            sub_m.value_of("OUTPUT").unwrap()
+            sub_m.value_of("OUTPUT").unwrap(),
+            refpack_mode(sub_m.value_of("mode").unwrap())
         )?,
         _ => println!("Unknown subcommand")
     }
@@ -281,37 +834,114 @@ fn refpack(matches: &ArgMatches, start_offset: usize) -> Result<()> {
     Ok(())
 }
 
+fn refpack_mode(name: &str) -> format::RefPackMode {
+    match name {
+        "greedy" => format::RefPackMode::Greedy,
+        _ => format::RefPackMode::Literal,
+    }
+}
+
 fn refpack_uncompress(input: &str, output: &str, start_offset: usize) -> Result<()> {
     fs::write(output, format::RefPackCompression::uncompress(&fs::read(input)?[start_offset..])?)?;
     Ok(())
 }
 
-fn refpack_compress(input: &str, output: &str) -> Result<()> {
+fn refpack_compress(input: &str, output: &str, mode: format::RefPackMode) -> Result<()> {
     let data = fs::read(input)?;
-    let compress = format::RefPackCompression::compress(&data)?;
+    let compress = format::RefPackCompression::compress_with_mode(&data, mode)?;
     assert_eq!(format::RefPackCompression::uncompress(&compress)?, data);
     fs::write(output, compress)?;
     Ok(())
 }
 
 fn image(matches: &ArgMatches, start_offset: usize) -> Result<()> {
-    let format = matches.value_of("type")
-        .map(|s| format::ImageType::from_game_value(s.parse::<u32>().unwrap()).unwrap())
-        .unwrap_or(format::ImageType::R5G6B5);
+    let format = match matches.value_of("type") {
+        Some(label) => format::ImageType::from_label(label)?,
+        None => format::ImageType::R5G6B5,
+    };
+
+    // Raw game images have no room for a palette, so P8 would lose it on import and could not be exported.
+    if format == format::ImageType::P8 {
+        return Err(Error::Image("paletted images are not supported by the image commands".into()));
+    }
+    let color_key = match matches.value_of("color-key") {
+        Some(s) => Some(parse_color(s)?),
+        None => None,
+    };
+    let layout = format::ImageLayout {
+        offset: start_offset,
+        stride: match matches.value_of("stride") {
+            Some(s) => Some(s.parse::<usize>().map_err(|x| Error::OtherError(Box::new(x)))?),
+            None => None,
+        },
+        row_order: if matches.is_present("bottom-up") {
+            format::RowOrder::BottomUp
+        } else {
+            format::RowOrder::TopDown
+        },
+        byte_order: if matches.is_present("big-endian") {
+            format::PixelByteOrder::BigEndian
+        } else {
+            format::PixelByteOrder::LittleEndian
+        },
+    };
 
     match matches.subcommand() {
-        ("to-png", Some(sub)) => image_to_png(
+        ("export", Some(sub)) => image_export(
             sub.value_of("INPUT").unwrap(),
             sub.value_of("OUTPUT").unwrap(),
-            start_offset,
+            &layout,
             format,
-            usize::from_str_radix(sub.value_of("WIDTH").unwrap(), 10).map_err(|x| Error::OtherError(Box::new(x)))?,
-            usize::from_str_radix(sub.value_of("HEIGHT").unwrap(), 10).map_err(|x| Error::OtherError(Box::new(x)))?
+            color_key,
+            sub.value_of("WIDTH").unwrap().parse::<usize>().map_err(|x| Error::OtherError(Box::new(x)))?,
+            sub.value_of("HEIGHT").unwrap().parse::<usize>().map_err(|x| Error::OtherError(Box::new(x)))?
+        )?,
+        ("guess", Some(sub)) => image_guess(
+            sub.value_of("INPUT").unwrap(),
+            start_offset,
+            sub.value_of("min-width").unwrap().parse::<usize>().map_err(|x| Error::OtherError(Box::new(x)))?,
+            sub.value_of("max-width").unwrap().parse::<usize>().map_err(|x| Error::OtherError(Box::new(x)))?,
+            sub.value_of("count").unwrap().parse::<usize>().map_err(|x| Error::OtherError(Box::new(x)))?,
+            sub.value_of("sheet")
+        )?,
+        ("get-preview", Some(sub)) => image_get_preview(
+            sub.value_of("INPUT").unwrap(),
+            sub.value_of("OUTPUT").unwrap(),
+            sub.value_of("record"),
+            color_key
+        )?,
+        ("set-preview", Some(sub)) => image_set_preview(
+            sub.value_of("INPUT").unwrap(),
+            sub.value_of("IMAGE").unwrap(),
+            sub.value_of("OUTPUT").unwrap(),
+            sub.value_of("record"),
+            sub.is_present("resize"),
+            color_key,
+            &quantize_options(sub.value_of("dither").unwrap(), !sub.is_present("no-serpentine"))
+        )?,
+        ("atlas-export", Some(sub)) => image_atlas_export(
+            sub.value_of("INPUT").unwrap(),
+            &sub.values_of("record-type").unwrap()
+                .map(|t| u32::from_str_radix(t, 16).map_err(|x| Error::OtherError(Box::new(x))))
+                .collect::<Result<Vec<u32>>>()?,
+            sub.value_of("ATLAS").unwrap()
         )?,
-        ("from-png", Some(sub)) => image_from_png(
+        ("atlas-import", Some(sub)) => image_atlas_import(
             sub.value_of("INPUT").unwrap(),
+            sub.value_of("ATLAS").unwrap(),
             sub.value_of("OUTPUT").unwrap(),
-            format
+            &quantize_options(sub.value_of("dither").unwrap(), !sub.is_present("no-serpentine"))
+        )?,
+        ("import", Some(sub)) => image_import(
+            sub.value_of("INPUT").unwrap(),
+            sub.value_of("OUTPUT").unwrap(),
+            &format::ImageLayout {
+                offset: 0,
+                ..layout.clone()
+            },
+            format,
+            color_key,
+            &quantize_options(sub.value_of("dither").unwrap(), !sub.is_present("no-serpentine"))
         )?,
         _ => println!("Unknown subcommand")
     }
@@ -319,50 +949,259 @@ fn image(matches: &ArgMatches, start_offset: usize) -> Result<()> {
     Ok(())
 }
 
-fn image_to_png(input: &str, output: &str, start_offset: usize, image_type: format::ImageType, width: usize,
-    height: usize) -> Result<()> {
-    let raw = &fs::read(input)?[start_offset..];
-    let image = format::Image::new(image_type, width, height, raw.to_vec())?;
+fn quantize_options(dither: &str, serpentine: bool) -> format::QuantizeOptions {
+    format::QuantizeOptions {
+        mode: match dither {
+            "ordered" => format::QuantizeMode::Ordered,
+            "floyd-steinberg" => format::QuantizeMode::FloydSteinberg,
+            _ => format::QuantizeMode::Nearest,
+        },
+        serpentine,
+    }
+}
+
+fn parse_color(s: &str) -> Result<[u8; 3]> {
+    let v = u32::from_str_radix(s.trim_start_matches('#'), 16).map_err(|x| Error::OtherError(Box::new(x)))?;
+
+    if s.trim_start_matches('#').len() != 6 {
+        return Err(Error::from(format!("invalid colour (expected RRGGBB): {}", s)));
+    }
+
+    Ok([(v >> 16) as u8, (v >> 8) as u8, v as u8])
+}
+
+fn image_export(input: &str, output: &str, layout: &format::ImageLayout, image_type: format::ImageType,
+    color_key: Option<[u8; 3]>, width: usize, height: usize) -> Result<()> {
+    let image = format::Image::from_raw(image_type, width, height, &fs::read(input)?, layout)?;
+
+    save_image(&image.to_dynamic_image(color_key), output)
+}
+
+fn image_guess(input: &str, start_offset: usize, min_width: usize, max_width: usize, count: usize,
+    sheet: Option<&str>) -> Result<()> {
+    let data = fs::read(input)?;
+    let data = data.get(start_offset..).ok_or_else(|| Error::from("start offset out of bounds"))?;
+    let image_types = [
+        format::ImageType::G1R5G5B5,
+        format::ImageType::ARGB1555,
+        format::ImageType::R5G6B5,
+        format::ImageType::ARGB4444,
+    ];
+    let mut guesses = format::ImageGuess::guess(data, min_width..max_width + 1, &image_types);
+
+    guesses.truncate(count);
+
+    for (i, guess) in guesses.iter().enumerate() {
+        println!("{:>3}. {}x{}, type {:?}, score {:.4}", i + 1, guess.width, guess.height, guess.image_type,
+            guess.score);
+    }
+
+    if let Some(sheet) = sheet {
+        write_contact_sheet(sheet, data, &guesses)?;
+    }
+
+    Ok(())
+}
+
+/// Draws each guess scaled to fit a cell, left to right and top to bottom.
+fn write_contact_sheet(output: &str, data: &[u8], guesses: &[format::ImageGuess]) -> Result<()> {
+    const CELL: u32 = 256;
+    const GAP: u32 = 4;
+    const COLUMNS: u32 = 4;
+
+    let columns = COLUMNS.min(guesses.len() as u32).max(1);
+    let rows = (guesses.len() as u32).div_ceil(columns).max(1);
+    let mut sheet = image::RgbImage::new(columns * (CELL + GAP) + GAP, rows * (CELL + GAP) + GAP);
+
+    for (i, guess) in guesses.iter().enumerate() {
+        let candidate = format::Image::from_raw(guess.image_type, guess.width, guess.height, data,
+            &format::ImageLayout::default())?.to_dynamic_image(None).to_rgb8();
+        let scale = (CELL as f64 / guess.width.max(guess.height) as f64).min(1.0);
+        let cell = image::imageops::resize(&candidate, ((guess.width as f64 * scale) as u32).max(1),
+            ((guess.height as f64 * scale) as u32).max(1), image::imageops::FilterType::Nearest);
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+
+        image::imageops::replace(&mut sheet, &cell, (GAP + column * (CELL + GAP)) as i64,
+            (GAP + row * (CELL + GAP)) as i64);
+    }
+
+    sheet.save(output).map_err(|x| Error::OtherError(Box::new(x)))
+}
+
+fn find_preview(ixf: &format::IXFFile, record: Option<&str>) -> Result<(usize, format::PreviewImage)> {
+    match record {
+        Some(tgi) => {
+            let (type_id, group_id, instance_id) = parse_tgi(tgi)?;
+            format::PreviewImage::find_by_tgi(ixf, type_id, group_id, instance_id)
+        },
+        None => format::PreviewImage::find(ixf),
+    }
+}
+
+fn image_get_preview(input: &str, output: &str, record: Option<&str>, color_key: Option<[u8; 3]>) -> Result<()> {
+    let ixf = format::IXFFile::parse(&fs::read(input)?, false)?;
+    let (index, preview) = find_preview(&ixf, record)?;
+    let record = &ixf.records[index];
+
+    println!("Found preview image in record {:X?}_{:X?}_{:X?}: {}x{}, type {:?}", record.type_id, record.group_id,
+        record.instance_id, preview.image.width(), preview.image.height(), preview.image.image_type());
+
+    save_image(&preview.image.to_dynamic_image(color_key), output)
+}
+
+fn image_set_preview(input: &str, image: &str, output: &str, record: Option<&str>, resize: bool,
+    color_key: Option<[u8; 3]>, options: &format::QuantizeOptions) -> Result<()> {
+    let mut ixf = format::IXFFile::parse(&fs::read(input)?, false)?;
+    let (index, mut preview) = find_preview(&ixf, record)?;
+    let (width, height) = (preview.image.width() as u32, preview.image.height() as u32);
+
+    let mut image = load_image(image)?;
+
+    if image.dimensions() != (width, height) {
+        if !resize {
+            return Err(Error::Image(format!("preview image must be {}x{}, got {}x{} (use --resize)", width, height,
+                image.width(), image.height())));
+        }
+
+        image = image.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
+    }
+
+    let image = format::Image::from_dynamic_image(&image, preview.image.image_type(), color_key, options)?;
+
+    match preview.replace_image(image)? {
+        Some(body) => ixf.records[index].body = body,
+        None => println!("The preview image is unchanged, keeping the original record"),
+    }
+
+    fs::write(output, ixf.as_vec()?)?;
+
+    Ok(())
+}
+
+fn image_atlas_export(input: &str, type_ids: &[u32], atlas: &str) -> Result<()> {
+    let ixf = format::IXFFile::parse(&fs::read(input)?, false)?;
+    let (image, index) = format::AtlasIndex::pack(&ixf, type_ids)?;
 
-    image::save_buffer(
-        output,
-        &image.to_rgb8(),
-        image.width() as u32,
-        image.height() as u32,
-        image::ColorType::RGB(8)
-    )?;
+    if index.frames.is_empty() {
+        return Err(Error::Image("no records of the given types found".into()));
+    }
+
+    println!("Packed {} images into {}x{}", index.frames.len(), index.width, index.height);
+
+    save_image(&image::DynamicImage::ImageRgba8(image), atlas)?;
+    fs::write(Path::new(atlas).with_extension("json"), index.as_json()?)?;
 
     Ok(())
 }
 
-fn image_from_png(input: &str, output: &str, image_type: format::ImageType) -> Result<()> {
-    let png = image::load(BufReader::new(File::open(input)?), image::ImageFormat::PNG)
-                .map_err(|x| Error::OtherError(Box::new(x)))?
-                .to_rgb();
-    let (width, height) = png.dimensions();
+fn image_atlas_import(input: &str, atlas: &str, output: &str, options: &format::QuantizeOptions) -> Result<()> {
+    let mut ixf = format::IXFFile::parse(&fs::read(input)?, false)?;
+    let index = format::AtlasIndex::parse_json(&fs::read_to_string(Path::new(atlas).with_extension("json"))?)?;
 
-    fs::write(
-        output,
-        &format::Image::from_rgb8(
-            &png.into_raw(),
-            width as usize,
-            height as usize,
-            image_type
-        )?.into_inner()
-    )?;
+    let changed = index.unpack(&load_image(atlas)?.to_rgba8(), &mut ixf, options)?;
 
+    println!("Updated {} of {} images", changed, index.frames.len());
+    fs::write(output, ixf.as_vec()?)?;
+
+    Ok(())
+}
+
+/// Saves an image in the format given by the extension of `path`. GIF holds at most 256 colours and would quantize
+/// the rest, so images with more are an error rather than a file that does not import back the same.
+fn save_image(image: &image::DynamicImage, path: &str) -> Result<()> {
+    let is_gif = Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case("gif"));
+
+    if is_gif {
+        let colors = image.to_rgba8().pixels().map(|p| p.0).collect::<std::collections::HashSet<[u8; 4]>>();
+
+        if colors.len() > 256 {
+            return Err(Error::Image(format!("{} colours do not fit in a GIF (at most 256); use PNG, BMP or TGA",
+                colors.len())));
+        }
+    }
+
+    image.save(path).map_err(|x| Error::OtherError(Box::new(x)))
+}
+
+/// Loads an image, detecting its format from the content or, failing that, the file extension.
+fn load_image(path: &str) -> Result<image::DynamicImage> {
+    image::io::Reader::open(path)?
+        .with_guessed_format()?
+        .decode()
+        .map_err(|x| Error::OtherError(Box::new(x)))
+}
+
+fn image_import(input: &str, output: &str, layout: &format::ImageLayout, image_type: format::ImageType,
+    color_key: Option<[u8; 3]>, options: &format::QuantizeOptions) -> Result<()> {
+    let image = format::Image::from_dynamic_image(&load_image(input)?, image_type, color_key, options)?;
+    fs::write(output, image.to_raw(layout)?)?;
     Ok(())
 }
 
 fn pak(matches: &ArgMatches) -> Result<()> {
+    let encoding = match matches.value_of("encoding").unwrap() {
+        "auto" => None,
+        label => Some(format::PAKFile::encoding_for_label(label)?),
+    };
+
     match matches.subcommand() {
         ("extract", Some(sub_m)) => pak_extract(
             sub_m.value_of("INPUT").unwrap(),
-            sub_m.value_of("OUTPUT").unwrap()
+            sub_m.value_of("OUTPUT").unwrap(),
+            encoding
         )?,
         ("reconstruct", Some(sub_m)) => pak_reconstruct(
             sub_m.value_of("INPUT").unwrap(),
-            sub_m.value_of("OUTPUT").unwrap()
+            sub_m.value_of("OUTPUT").unwrap(),
+            encoding,
+            sub_m.is_present("deduplicate")
+        )?,
+        ("export", Some(sub_m)) => pak_export(
+            sub_m.value_of("INPUT").unwrap(),
+            sub_m.value_of("OUTPUT").unwrap(),
+            &structured_format(sub_m.value_of("format"), sub_m.value_of("OUTPUT").unwrap())?,
+            encoding
+        )?,
+        ("import", Some(sub_m)) => pak_import(
+            sub_m.value_of("INPUT").unwrap(),
+            sub_m.value_of("OUTPUT").unwrap(),
+            &structured_format(sub_m.value_of("format"), sub_m.value_of("INPUT").unwrap())?,
+            encoding,
+            sub_m.is_present("deduplicate")
+        )?,
+        ("compare", Some(sub_m)) => pak_compare(
+            sub_m.value_of("BASE").unwrap(),
+            sub_m.value_of("TRANSLATED").unwrap(),
+            match sub_m.value_of("base-encoding") {
+                Some(label) => Some(format::PAKFile::encoding_for_label(label)?),
+                None => encoding,
+            },
+            match sub_m.value_of("translated-encoding") {
+                Some(label) => Some(format::PAKFile::encoding_for_label(label)?),
+                None => encoding,
+            }
+        )?,
+        ("get", Some(sub_m)) => pak_get(
+            sub_m.value_of("INPUT").unwrap(),
+            &sub_m.values_of("NAME").unwrap().collect::<Vec<&str>>(),
+            encoding
+        )?,
+        ("to-po", Some(sub_m)) => pak_to_po(
+            sub_m.value_of("INPUT").unwrap(),
+            sub_m.value_of("OUTPUT").unwrap(),
+            encoding
+        )?,
+        ("from-po", Some(sub_m)) => pak_from_po(
+            sub_m.value_of("INPUT").unwrap(),
+            sub_m.value_of("PO").unwrap(),
+            sub_m.value_of("OUTPUT").unwrap(),
+            encoding,
+            match sub_m.value_of("output-encoding") {
+                Some(label) => Some(format::PAKFile::encoding_for_label(label)?),
+                None => None,
+            },
+            sub_m.is_present("fuzzy"),
+            sub_m.is_present("deduplicate")
         )?,
         _ => println!("Unknown subcommand")
     }
@@ -370,19 +1209,323 @@ fn pak(matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn pak_extract(input: &str, output: &str) -> Result<()> {
+fn read_pak(input: &str, encoding: Option<&'static Encoding>) -> Result<format::PAKFile> {
+    let data = fs::read(input)?;
+
+    match encoding {
+        Some(encoding) => format::PAKFile::parse_with_encoding(&data, encoding),
+        None => {
+            let pak = format::PAKFile::parse(&data)?;
+
+            if pak.is_ascii() {
+                println!("Detected encoding: {} (the file is plain ASCII, so its code page is unknown)",
+                    pak.encoding.name());
+            } else {
+                println!("Detected encoding: {}", pak.encoding.name());
+            }
+
+            Ok(pak)
+        },
+    }
+}
+
+/// Holds the encoding of an extracted PAK file on the first line, then one line per record in the original order: the
+/// escaped record name (see `PAKRecord::escape`), a tab and the path of the file that holds the record. Records that
+/// share the line block of an earlier record end with another tab and the index of that record.
+const PAK_INDEX_FILENAME: &str = ".pakindex";
+
+fn pak_extract(input: &str, output: &str, encoding: Option<&'static Encoding>) -> Result<()> {
     let basepath = Path::new(output);
-    let pak = format::PAKFile::parse(&fs::read(input)?)?;
+    let pak = read_pak(input, encoding)?;
+    let paths = pak.extraction_paths(&[PAK_INDEX_FILENAME]);
+    let mut index = String::new();
+
+    writeln!(index, "{}", pak.encoding.name()).unwrap();
+
+    for (i, (record, path)) in pak.records.iter().zip(paths.iter()).enumerate() {
+        let filename = basepath.join(path);
+
+        if let Some(parent) = filename.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(filename, record.as_escaped_text())?;
+        write!(index, "{}\t{}", format::PAKRecord::escape(&record.name), path).unwrap();
+
+        match pak.shared_blocks.get(i) {
+            Some(&block) if block != i => writeln!(index, "\t{}", block).unwrap(),
+            _ => writeln!(index).unwrap(),
+        }
+    }
+
+    fs::write(basepath.join(PAK_INDEX_FILENAME), index)?;
+
+    Ok(())
+}
+
+fn pak_reconstruct(input: &str, output: &str, encoding: Option<&'static Encoding>, deduplicate: bool)
+    -> Result<()> {
+    let basepath = Path::new(input);
+    let index = fs::read_to_string(basepath.join(PAK_INDEX_FILENAME))?;
+    let mut entries = index.lines();
+    let extracted_encoding = format::PAKFile::encoding_for_label(entries.next().unwrap_or_default())?;
+    let mut pak = format::PAKFile::new(encoding.unwrap_or(extracted_encoding), Vec::new());
+    let mut shared_blocks = Vec::new();
+
+    for (i, entry) in entries.enumerate() {
+        let invalid = || Error::PAKFile(format!("invalid index entry: {:?}", entry));
+        let mut fields = entry.split('\t');
+        let (name, path, block) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(name), Some(path), block, None) if format::PAKRecord::is_safe_path(path) => (name, path, block),
+            _ => return Err(invalid()),
+        };
+        let name = format::PAKRecord::unescape(name)?;
+
+        pak.records.push(format::PAKRecord::from_escaped_text(&name, &fs::read_to_string(basepath.join(path))?)?);
+        shared_blocks.push(match block {
+            Some(block) => block.parse::<usize>().map_err(|_| invalid())?,
+            None => i,
+        });
+    }
+
+    if shared_blocks.iter().enumerate().any(|(i, &j)| i != j) {
+        pak.shared_blocks = shared_blocks;
+    }
+
+    if encoding.is_none() {
+        check_unasked_utf8(&pak, "--encoding")?;
+    }
+
+    write_pak(output, &pak, deduplicate)
+}
+
+/// Plain ASCII files are detected as UTF-8 (see `PAKFile::is_ascii`), which localized versions of the game do not
+/// use, so writing non-ASCII text as UTF-8 needs the encoding to be given with `option`.
+fn check_unasked_utf8(pak: &format::PAKFile, option: &str) -> Result<()> {
+    if pak.encoding == encoding_rs::UTF_8 && !pak.is_ascii() {
+        return Err(Error::from(format!("the text is not plain ASCII and no encoding was given; pass the code page \
+            of the text with {} (utf-8 if UTF-8 is really wanted)", option)));
+    }
+
+    Ok(())
+}
+
+/// Writes a PAK file, keeping the line blocks that were shared when it was read (see `PAKFile::shared_blocks`) unless
+/// told to let every record with identical lines share one.
+fn write_pak(output: &str, pak: &format::PAKFile, deduplicate: bool) -> Result<()> {
+    let data = if deduplicate { pak.as_vec_with_sharing(&format::PAKSharing::Identical)? } else { pak.as_vec()? };
+
+    fs::write(output, data)?;
+
+    Ok(())
+}
+
+/// Takes the format from the argument, or else from the extension of `path`.
+fn structured_format(format: Option<&str>, path: &str) -> Result<String> {
+    let format = format.map(String::from)
+        .or_else(|| Path::new(path).extension().map(|e| e.to_string_lossy().to_lowercase()))
+        .unwrap_or_default();
+
+    match format.as_str() {
+        "json" | "csv" | "toml" => Ok(format),
+        _ => Err(Error::from(format!("unknown format for {} (use --format json, csv or toml)", path))),
+    }
+}
+
+fn pak_export(input: &str, output: &str, format: &str, encoding: Option<&'static Encoding>) -> Result<()> {
+    let pak = read_pak(input, encoding)?;
+    let text = match format {
+        "json" => pak.as_json()?,
+        "csv" => pak.as_csv()?,
+        _ => pak.as_toml()?,
+    };
+
+    fs::write(output, text)?;
+
+    Ok(())
+}
+
+fn pak_import(input: &str, output: &str, format: &str, encoding: Option<&'static Encoding>, deduplicate: bool)
+    -> Result<()> {
+    let text = fs::read_to_string(input)?;
+    let mut pak = match format {
+        "json" => format::PAKFile::parse_json(&text)?,
+        "csv" => format::PAKFile::parse_csv(&text)?,
+        _ => format::PAKFile::parse_toml(&text)?,
+    };
 
-    for record in pak.records.iter() {
-        fs::write(basepath.join(&record.name), record.as_single_string())?
+    match encoding {
+        Some(encoding) => pak.encoding = encoding,
+        None => check_unasked_utf8(&pak, "--encoding")?,
     }
 
+    write_pak(output, &pak, deduplicate)
+}
+
+fn pak_compare(base: &str, translated: &str, base_encoding: Option<&'static Encoding>,
+    translated_encoding: Option<&'static Encoding>) -> Result<()> {
+    let comparison = format::PAKComparison::compare(&read_pak(base, base_encoding)?,
+        &read_pak(translated, translated_encoding)?);
+
+    let sections = [
+        ("Missing in the translation", comparison.missing_in_translation.clone()),
+        ("Missing in the base", comparison.missing_in_base.clone()),
+        ("Different line counts (lines not compared)", comparison.line_counts.iter()
+            .map(|m| format!("{}: {} -> {} lines", m.record, m.base, m.translated))
+            .collect()),
+        ("Untranslated", comparison.untranslated.iter()
+            .map(|(record, line)| format!("{}:{}", record, line))
+            .collect()),
+        ("Mismatched placeholders", comparison.placeholders.iter()
+            .map(|m| {
+                let list = |p: &[String]| if p.is_empty() { "(none)".into() } else { p.join(" ") };
+                format!("{}:{}: {} -> {}", m.record, m.line, list(&m.base), list(&m.translated))
+            })
+            .collect()),
+    ];
+
+    for (title, items) in sections.iter() {
+        if !items.is_empty() {
+            println!("{}: {}", title, items.len());
+
+            for item in items.iter() {
+                println!("    {}", item);
+            }
+        }
+    }
+
+    println!("Coverage: {:.1}% of {} lines", comparison.coverage() * 100.0, comparison.compared_lines);
+
     Ok(())
 }
 
-fn pak_reconstruct(input: &str, output: &str) -> Result<()> {
-    unimplemented!()
+fn pak_get(input: &str, names: &[&str], encoding: Option<&'static Encoding>) -> Result<()> {
+    let data = fs::read(input)?;
+    let reader = match encoding {
+        Some(encoding) => format::PAKReader::with_encoding(&data, encoding)?,
+        None => {
+            let reader = format::PAKReader::new(&data)?;
+
+            println!("Detected encoding: {}", reader.encoding().name());
+            reader
+        },
+    };
+
+    for name in names.iter() {
+        let record = reader.get(name)?
+            .ok_or_else(|| Error::PAKFile(format!("no record named {:?}", name)))?;
+
+        println!("[{}]", format::PAKRecord::escape(name));
+        print!("{}", record.as_escaped_text());
+    }
+
+    Ok(())
+}
+
+fn pak_to_po(input: &str, output: &str, encoding: Option<&'static Encoding>) -> Result<()> {
+    let po = format::POFile::from_pak(&read_pak(input, encoding)?);
+
+    println!("Exported {} strings", po.entries.len());
+    fs::write(output, po.as_string())?;
+
+    Ok(())
+}
+
+fn pak_from_po(input: &str, po: &str, output: &str, encoding: Option<&'static Encoding>,
+    output_encoding: Option<&'static Encoding>, use_fuzzy: bool, deduplicate: bool) -> Result<()> {
+    let mut pak = read_pak(input, encoding)?;
+    let po = format::POFile::parse(&fs::read_to_string(po)?)?;
+    let report = po.merge_into(&mut pak, use_fuzzy);
+
+    let groups = [
+        (if use_fuzzy { "Fuzzy (merged)" } else { "Fuzzy (skipped)" }, &report.fuzzy),
+        ("Untranslated", &report.untranslated),
+        ("Source text changed (skipped)", &report.changed),
+        ("Not in the PAK file (skipped)", &report.unknown),
+    ];
+
+    for &(title, contexts) in groups.iter() {
+        if !contexts.is_empty() {
+            println!("{}: {}", title, contexts.len());
+
+            for msgctxt in contexts.iter() {
+                println!("    {}", msgctxt);
+            }
+        }
+    }
+
+    println!("Translated: {}", report.translated);
+
+    match output_encoding {
+        Some(encoding) => pak.encoding = encoding,
+        None if encoding.is_none() => check_unasked_utf8(&pak, "--output-encoding")?,
+        None => (),
+    }
+
+    write_pak(output, &pak, deduplicate)
+}
+
+fn search(matches: &ArgMatches) -> Result<()> {
+    let kind = if matches.is_present("regex") {
+        format::SearchPatternKind::Regex
+    } else if matches.is_present("hex") {
+        format::SearchPatternKind::Hex
+    } else {
+        format::SearchPatternKind::Text
+    };
+    let searcher = format::Searcher::new(matches.value_of("PATTERN").unwrap(), kind,
+        matches.is_present("ignore-case"))?;
+
+    for path in matches.values_of("PATH").unwrap() {
+        search_path(&searcher, Path::new(path), kind == format::SearchPatternKind::Hex)?;
+    }
+
+    Ok(())
+}
+
+fn search_path(searcher: &format::Searcher, path: &Path, show_hex: bool) -> Result<()> {
+    if path.is_dir() {
+        let mut entries = fs::read_dir(path)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        entries.sort();
+
+        for entry in entries {
+            search_path(searcher, &entry, show_hex)?;
+        }
+
+        return Ok(());
+    }
+
+    let is_pak = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("pak"));
+    let hits = match searcher.search_file(&fs::read(path)?, is_pak) {
+        Ok(hits) => hits,
+        Err(e) => {
+            eprintln!("{}: skipped: {}", path.display(), e);
+            return Ok(());
+        },
+    };
+
+    for hit in hits {
+        let location = match hit.location {
+            format::SearchLocation::File => String::new(),
+            format::SearchLocation::PAKLine { record, line } =>
+                format!(" record {} line {}", format::PAKRecord::escape(&record), line),
+            format::SearchLocation::IXFRecord { type_id, group_id, instance_id, compressed } =>
+                format!(" record {:X?}_{:X?}_{:X?}{}", type_id, group_id, instance_id,
+                    if compressed { " (uncompressed)" } else { "" }),
+        };
+        let matched = if show_hex {
+            hit.matched.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ")
+        } else {
+            format::PAKRecord::escape(&String::from_utf8_lossy(&hit.matched))
+        };
+
+        println!("{}:{} offset 0x{:X}: {}", path.display(), location, hit.offset, matched);
+    }
+
+    Ok(())
 }
 
 fn dump_hex(data: &[u8]) -> String {
@@ -402,12 +1545,12 @@ fn dump_hex(data: &[u8]) -> String {
             }
 
             ascii.push(match b {
-                0x20 ... 0x7e => b as char,
+                0x20..=0x7e => b as char,
                 _ => '.'
             });
         }
 
-        write!(output, "{:016X} {:50}{}\n", i << 4, numeric, ascii).unwrap();
+        writeln!(output, "{:016X} {:50}{}", i << 4, numeric, ascii).unwrap();
     }
 
     output
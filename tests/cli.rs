@@ -0,0 +1,135 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn toolsc3k(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_toolsc3k")).args(args).output().unwrap()
+}
+
+/// A fresh directory for the files of one test.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("toolsc3k-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn image_round_trip() {
+    let dir = temp_dir("image");
+    let raw = dir.join("in.bin");
+    let png = dir.join("out.png");
+    let back = dir.join("back.bin");
+    let data = (0..4 * 3 * 3).map(|x| (x * 37) as u8).collect::<Vec<u8>>();
+
+    for &image_type in ["r5g6b5", "g1r5g5b5", "argb4444", "rgb888"].iter() {
+        let data = &data[..4 * 3 * if image_type == "rgb888" { 3 } else { 2 }];
+        fs::write(&raw, data).unwrap();
+
+        let export = toolsc3k(&["image", "-t", image_type, "export", "4", "3", raw.to_str().unwrap(),
+            png.to_str().unwrap()]);
+        assert!(export.status.success(), "{}", String::from_utf8_lossy(&export.stderr));
+
+        let import = toolsc3k(&["image", "-t", image_type, "import", png.to_str().unwrap(), back.to_str().unwrap()]);
+        assert!(import.status.success(), "{}", String::from_utf8_lossy(&import.stderr));
+
+        assert_eq!(fs::read(&back).unwrap(), data, "{}", image_type);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn image_rejects_paletted() {
+    let dir = temp_dir("paletted");
+    let raw = dir.join("in.bin");
+    let png = dir.join("out.png");
+
+    fs::write(&raw, [0u8; 4]).unwrap();
+
+    assert!(!toolsc3k(&["image", "-t", "p8", "export", "2", "2", raw.to_str().unwrap(), png.to_str().unwrap()])
+        .status.success());
+    assert!(!png.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn image_gif_too_many_colors() {
+    let dir = temp_dir("gif");
+    let raw = dir.join("in.bin");
+    let gif = dir.join("out.gif");
+    let few = (0..64 * 64).flat_map(|x| [(x % 200) as u8, 0]).collect::<Vec<u8>>();
+    let many = (0..64 * 64).flat_map(|x| [x as u8, (x >> 8) as u8]).collect::<Vec<u8>>();
+
+    fs::write(&raw, few).unwrap();
+    assert!(toolsc3k(&["image", "export", "64", "64", raw.to_str().unwrap(), gif.to_str().unwrap()]).status.success());
+
+    fs::remove_file(&gif).unwrap();
+    fs::write(&raw, many).unwrap();
+    assert!(!toolsc3k(&["image", "export", "64", "64", raw.to_str().unwrap(), gif.to_str().unwrap()]).status.success());
+    assert!(!gif.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn pak_compare_encodings() {
+    let dir = temp_dir("compare");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    fs::write(path("base.json"), r#"{"encoding": "windows-1252", "records": [{"name": "a", "lines": ["Café"]}]}"#)
+        .unwrap();
+    fs::write(path("translated.json"),
+        r#"{"encoding": "windows-1250", "records": [{"name": "a", "lines": ["Šťastná kavárna"]}]}"#).unwrap();
+
+    for name in ["base", "translated"].iter() {
+        let import = toolsc3k(&["pak", "import", &path(&format!("{}.json", name)), &path(&format!("{}.pak", name))]);
+        assert!(import.status.success(), "{}", String::from_utf8_lossy(&import.stderr));
+    }
+
+    let compare = toolsc3k(&["pak", "compare", "--base-encoding", "cp1252", "--translated-encoding", "cp1250",
+        &path("base.pak"), &path("translated.pak")]);
+    let stdout = String::from_utf8_lossy(&compare.stdout);
+
+    assert!(compare.status.success(), "{}", String::from_utf8_lossy(&compare.stderr));
+    assert!(stdout.contains("Coverage: 100.0% of 1 lines"), "{}", stdout);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn pak_round_trip_keeps_sharing() {
+    let dir = temp_dir("sharing");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let run = |args: &[&str]| {
+        let output = toolsc3k(args);
+        assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    };
+
+    // Records 0 and 2 share a block; record 1 has the same lines in a block of its own.
+    fs::write(path("in.json"), r#"{"encoding": "UTF-8", "shared_blocks": [0, 1, 0],
+        "records": [{"name": "a", "lines": ["x"]}, {"name": "b", "lines": ["x"]}, {"name": "c", "lines": ["x"]}]}"#)
+        .unwrap();
+    run(&["pak", "import", &path("in.json"), &path("in.pak")]);
+
+    let original = fs::read(path("in.pak")).unwrap();
+
+    run(&["pak", "extract", &path("in.pak"), &path("extracted")]);
+    run(&["pak", "reconstruct", &path("extracted"), &path("reconstructed.pak")]);
+    assert_eq!(fs::read(path("reconstructed.pak")).unwrap(), original);
+
+    for format in ["json", "csv", "toml"].iter() {
+        let exported = path(&format!("out.{}", format));
+
+        run(&["pak", "export", &path("in.pak"), &exported]);
+        run(&["pak", "import", &exported, &path("imported.pak")]);
+        assert_eq!(fs::read(path("imported.pak")).unwrap(), original, "{}", format);
+    }
+
+    run(&["pak", "reconstruct", "--deduplicate", &path("extracted"), &path("deduplicated.pak")]);
+    assert_eq!(fs::read(path("deduplicated.pak")).unwrap().len(), original.len() - 9);
+
+    fs::remove_dir_all(&dir).unwrap();
+}